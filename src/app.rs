@@ -1,8 +1,8 @@
-use log::error;
-use nom::{branch::alt, bytes::complete::tag, bytes::streaming::take_until, IResult};
+use nom::{bytes::complete::tag, IResult};
 use serde::Deserialize;
 use std::collections::BTreeMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::io::BufRead;
 use std::option::Option;
 // use crossbeam_channel::{ self, unbounded, Sender, Receiver };
@@ -10,7 +10,13 @@ use async_recursion::async_recursion;
 use tokio::sync::mpsc;
 
 use super::db;
+use super::encoding;
+use super::eval;
+use super::explain;
+use super::filter;
+use super::input;
 use super::parser;
+use super::record;
 
 #[derive(Debug, Deserialize, Clone)]
 pub enum VarExpr {
@@ -21,63 +27,196 @@ pub enum VarExpr {
 #[derive(Debug, Deserialize, Clone)]
 pub struct TokenExpr {
     pub tag: String,
+    pub name: Option<String>,
     vars: Option<BTreeMap<String, VarExpr>>,
 }
 
 pub type Token = TokenExpr;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Capture<'a> {
+    key: Option<&'a str>,
+    format: Option<&'a str>,
+    width: Option<(usize, bool)>,
+    trim: bool,
+    kv: bool,
+    csv: bool,
+    json: Option<Option<&'a str>>,
+    flatten: bool,
+    column_type: Option<db::ColumnType>,
+}
+
 pub type DB = Vec<BTreeMap<String, String>>;
 
+#[derive(Debug, Default, Clone)]
+pub struct Record {
+    pub fields: BTreeMap<String, String>,
+    pub items: DB,
+}
+
 impl TokenExpr {
     pub fn new_with_tag(tag: &String) -> TokenExpr {
         TokenExpr {
             tag: tag.clone(),
+            name: None,
             vars: None,
         }
     }
 
+    pub fn from_file(path: &str) -> anyhow::Result<TokenExpr> {
+        let source = std::fs::read_to_string(path)?;
+        let syn = parser::Syntax::default();
+        let path = std::path::Path::new(path);
+
+        TokenExpr {
+            tag: parser::join_lines(&source, &syn),
+            name: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string()),
+            vars: None,
+        }
+        .expand(path.parent().unwrap_or_else(|| std::path::Path::new(".")))
+    }
+
+    pub fn expand(mut self, dir: &std::path::Path) -> anyhow::Result<TokenExpr> {
+        self.tag = parser::expand(&self.tag, &parser::Syntax::default(), dir)?;
+        Ok(self)
+    }
+
+    pub fn nodes<'a>(&'a self, syn: &'a parser::Syntax) -> anyhow::Result<Vec<parser::Node<'a>>> {
+        parser::parse(self.tag.as_bytes(), syn)
+            .map(|(_, tokens)| tokens)
+            .map_err(|e| anyhow::anyhow!("{:?}: {}", e, self.tag))
+    }
+
     pub async fn evaluate(
-        &self,
-        rx: &mut mpsc::UnboundedReceiver<InputToken>,
-        syn: &parser::Syntax,
-    ) -> (bool, DB) {
-        let mut results = Vec::default();
+        rx: &mut LineBuffer<'_>,
+        tokens: &[parser::Node<'_>],
+    ) -> Option<Vec<Record>> {
+        let text = rx.next().await?;
 
-        match rx.recv().await {
-            Some(InputToken::Channel(text)) => {
-                if let Ok((_, mut result)) = self.parse(rx, &text[..], syn).await {
-                    results.append(&mut result);
+        match Self::parse(rx, &text[..], tokens).await {
+            Ok((_, records)) => {
+                rx.commit();
+                Some(records)
+            }
+            Err(_) => {
+                rx.rollback();
+                None
+            }
+        }
+    }
 
-                    loop {
-                        match rx.recv().await {
-                            Some(InputToken::Channel(text)) => {
-                                if let Ok((_, mut row)) = self.parse(rx, &text[..], syn).await {
-                                    results.append(&mut row);
-                                } else {
-                                    break;
-                                }
-                            }
-                            Some(InputToken::Byte(b'\0')) => return (true, results),
-                            _ => break,
+    pub async fn parse(
+        rx: &mut LineBuffer<'_>,
+        text: &str,
+        tokens: &[parser::Node<'_>],
+    ) -> IResult<String, Vec<Record>> {
+        rx.failed.clear();
+
+        Self::parse_token(rx, text, tokens, &[], &BTreeMap::default()).await
+    }
+
+    pub fn column_types(&self, syn: &parser::Syntax) -> BTreeMap<String, db::ColumnType> {
+        let mut types = BTreeMap::default();
+
+        if let Ok((_, tokens)) = parser::parse(self.tag.as_bytes(), syn) {
+            Self::collect_column_types(&tokens, &mut types);
+        }
+
+        types
+    }
+
+    pub fn prefix(tokens: &[parser::Node<'_>]) -> String {
+        match tokens.first() {
+            Some(parser::Node::Lit(a, b, c)) => format!("{}{}{}", a, b, c),
+            Some(parser::Node::Expr(_, parser::Expr::StrLit(lit), _))
+            | Some(parser::Node::Expr(_, parser::Expr::CharLit(lit), _)) => unescape(lit),
+            _ => String::default(),
+        }
+    }
+
+    pub fn has_loop(&self, syn: &parser::Syntax) -> bool {
+        self.any_node(syn, &|token| matches!(token, parser::Node::Loop(..)))
+    }
+
+    pub fn has_dynamic_columns(&self, syn: &parser::Syntax) -> bool {
+        self.any_node(syn, &|token| match token {
+            parser::Node::Expr(_, expr, _) => {
+                Self::capture(expr).is_some_and(|c| c.kv || c.flatten)
+            }
+            _ => false,
+        })
+    }
+
+    fn any_node(&self, syn: &parser::Syntax, f: &dyn Fn(&parser::Node) -> bool) -> bool {
+        fn any(tokens: &[parser::Node], f: &dyn Fn(&parser::Node) -> bool) -> bool {
+            tokens.iter().any(|token| {
+                f(token)
+                    || match token {
+                        parser::Node::Cond(exprs, _) => exprs.iter().any(|(_, _, ns)| any(ns, f)),
+                        parser::Node::Loop(_, _, _, ns, _) | parser::Node::Optional(_, ns, _) => {
+                            any(ns, f)
                         }
+                        _ => false,
                     }
-                }
-            }
-            Some(InputToken::Byte(b'\0')) => return (true, results),
-            _ => {}
+            })
         }
 
-        (false, results)
+        parser::parse(self.tag.as_bytes(), syn)
+            .map(|(_, tokens)| any(&tokens, f))
+            .unwrap_or_default()
     }
 
-    pub async fn parse<'a>(
-        &self,
-        rx: &mut mpsc::UnboundedReceiver<InputToken>,
-        text: &'a str,
-        syn: &parser::Syntax,
-    ) -> IResult<String, DB> {
-        let (_, tokens) = parser::parse_template(self.tag.as_bytes(), &syn).unwrap();
+    fn collect_column_types(tokens: &[parser::Node], types: &mut BTreeMap<String, db::ColumnType>) {
+        for token in tokens.iter() {
+            match token {
+                parser::Node::Expr(_, expr, _) => {
+                    if let Some(Capture {
+                        key: Some(key),
+                        column_type: Some(column_type),
+                        ..
+                    }) = Self::capture(expr)
+                    {
+                        types.insert(key.to_string(), column_type);
+                    }
+                }
+                parser::Node::Cond(exprs, _) => {
+                    for (_, _, ns) in exprs.iter() {
+                        Self::collect_column_types(ns, types);
+                    }
+                }
+                parser::Node::Loop(_, target, parser::Expr::Range(..), nodes, _) => {
+                    let names = match target {
+                        parser::Target::Name(name) => vec![*name],
+                        parser::Target::Tuple(names) => names.iter().take(2).cloned().collect(),
+                    };
+                    for name in names.into_iter() {
+                        types.insert(name.to_string(), db::ColumnType::Int);
+                    }
 
-        Self::parse_token(rx, &text.to_string(), &tokens).await
+                    Self::collect_column_types(nodes, types);
+                }
+                parser::Node::Loop(_, _, _, nodes, _) => {
+                    Self::collect_column_types(nodes, types);
+                }
+                parser::Node::Optional(_, nodes, _) => {
+                    Self::collect_column_types(nodes, types);
+
+                    for node in nodes.iter() {
+                        if let Some(Capture { key: Some(key), .. }) = match node {
+                            parser::Node::Expr(_, expr, _) => Self::capture(expr),
+                            _ => None,
+                        } {
+                            types
+                                .entry(key.to_string())
+                                .or_insert(db::ColumnType::NullableText);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
     }
 
     fn merge<'a>(
@@ -97,94 +236,464 @@ impl TokenExpr {
         new_context
     }
 
-    async fn read_line(rx: &mut mpsc::UnboundedReceiver<InputToken>) -> String {
-        match rx.recv().await {
-            Some(InputToken::Channel(line)) => line,
-            _ => String::default(),
+    fn follow<'a>(
+        tokens: &'a [parser::Node<'a>],
+        idx: usize,
+        follow: &[&'a parser::Node<'a>],
+    ) -> Vec<&'a parser::Node<'a>> {
+        let mut next = Vec::default();
+
+        for token in tokens.iter().skip(idx + 1) {
+            match token {
+                parser::Node::Optional(_, nodes, _) => next.extend(nodes.first()),
+                parser::Node::Ws => next.push(token),
+                _ => {
+                    next.push(token);
+                    return next;
+                }
+            }
+        }
+
+        next.extend(follow.iter().cloned());
+        next
+    }
+
+    // Whether matching `tokens` can depend on values captured before them; failures of
+    // such a tail can not be cached by position alone.
+    fn reads_captures(tokens: &[parser::Node]) -> bool {
+        tokens.iter().any(|token| match token {
+            parser::Node::Cond(..) | parser::Node::Loop(_, _, parser::Expr::Range(..), _, _) => {
+                true
+            }
+            parser::Node::Loop(_, _, _, ns, _) | parser::Node::Optional(_, ns, _) => {
+                Self::reads_captures(ns)
+            }
+            _ => false,
+        })
+    }
+
+    async fn read_line(rx: &mut LineBuffer<'_>) -> String {
+        rx.next_continuation().await.unwrap_or_default()
+    }
+
+    fn repeat<'a>(iter: &parser::Expr<'a>) -> Option<Option<&'a str>> {
+        match iter {
+            parser::Expr::Var("repeat") => Some(None),
+            parser::Expr::Filter("sep", args) => match (args.first(), args.get(1)) {
+                (Some(parser::Expr::Var("repeat")), Some(parser::Expr::StrLit(sep))) => {
+                    Some(Some(sep))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn repeat_value(items: Vec<BTreeMap<String, String>>) -> String {
+        let keys = items
+            .iter()
+            .flat_map(|item| item.keys())
+            .collect::<HashSet<_>>();
+
+        if keys.len() == 1 {
+            let values = items
+                .into_iter()
+                .flat_map(|item| item.into_values())
+                .collect::<Vec<_>>();
+
+            serde_json::to_string(&values).unwrap_or_default()
+        } else {
+            serde_json::to_string(&items).unwrap_or_default()
         }
     }
 
-    #[async_recursion]
     async fn parse_token<'a>(
-        rx: &mut mpsc::UnboundedReceiver<InputToken>,
-        input: &String,
-        tokens: &Vec<parser::Node<'a>>,
-    ) -> IResult<String, DB> {
-        let mut input = input.to_string();
-        let mut h: BTreeMap<String, String> = BTreeMap::default();
+        rx: &mut LineBuffer<'_>,
+        input: &str,
+        tokens: &[parser::Node<'a>],
+        follow: &[&'a parser::Node<'a>],
+        vars: &BTreeMap<String, String>,
+    ) -> IResult<String, Vec<Record>> {
+        Self::parse_tokens(
+            rx,
+            input.to_string(),
+            tokens,
+            follow,
+            vars,
+            BTreeMap::default(),
+            Vec::default(),
+        )
+        .await
+    }
+
+    fn capture<'a>(expr: &parser::Expr<'a>) -> Option<Capture<'a>> {
+        match expr {
+            parser::Expr::Var(key) => Some(Capture {
+                key: Some(key),
+                ..Capture::default()
+            }),
+            parser::Expr::Filter(name, args) => {
+                let inner = Self::capture(args.first()?);
 
+                match (*name, args.get(1)) {
+                    ("skip", _) => Some(Capture {
+                        key: None,
+                        ..inner.unwrap_or_default()
+                    }),
+                    ("datetime", Some(parser::Expr::StrLit(format))) => Some(Capture {
+                        format: Some(format),
+                        column_type: Some(db::ColumnType::Timestamp),
+                        ..inner?
+                    }),
+                    ("int", None) => Some(Capture {
+                        column_type: Some(db::ColumnType::Int),
+                        ..inner?
+                    }),
+                    ("float", None) => Some(Capture {
+                        column_type: Some(db::ColumnType::Float),
+                        ..inner?
+                    }),
+                    ("width", Some(parser::Expr::NumLit(n)))
+                    | ("display_width", Some(parser::Expr::NumLit(n))) => Some(Capture {
+                        width: Some((n.parse().ok()?, *name == "display_width")),
+                        ..inner?
+                    }),
+                    ("trim", None) => Some(Capture {
+                        trim: true,
+                        ..inner?
+                    }),
+                    ("kv", None) => Some(Capture { kv: true, ..inner? }),
+                    ("csv", None) => Some(Capture {
+                        csv: true,
+                        ..inner?
+                    }),
+                    ("json", None) => Some(Capture {
+                        json: Some(None),
+                        ..inner?
+                    }),
+                    ("json", Some(parser::Expr::StrLit(path))) => Some(Capture {
+                        json: Some(Some(path)),
+                        column_type: Some(db::ColumnType::NullableText),
+                        ..inner?
+                    }),
+                    ("flatten", None) if inner?.json.is_some() => Some(Capture {
+                        flatten: true,
+                        ..inner?
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    #[async_recursion]
+    async fn parse_tokens<'a>(
+        rx: &mut LineBuffer<'_>,
+        mut input: String,
+        tokens: &[parser::Node<'a>],
+        follow: &[&'a parser::Node<'a>],
+        vars: &BTreeMap<String, String>,
+        mut h: BTreeMap<String, String>,
+        mut items: DB,
+    ) -> IResult<String, Vec<Record>> {
         for (idx, token) in tokens.iter().enumerate() {
             if input.is_empty() {
                 input = Self::read_line(rx).await;
             }
 
+            let next = Self::follow(tokens, idx, follow);
+
             match token {
                 parser::Node::Lit(a, b, c) => {
                     let a: IResult<&str, &str> =
                         tag(&format!("{}{}{}", a, b, c)[..])(input.as_str());
 
                     match a {
-                        Ok((rest, _b)) => input = rest.to_string(),
+                        Ok((rest, b)) => {
+                            rx.trace(|| explain::Step::Match(explain::node(token), b.to_string()));
+                            input = rest.to_string()
+                        }
                         _ => {
+                            rx.trace(|| explain::Step::Fail(explain::node(token), input.clone()));
                             return Err(
                                 default_error(input.as_str()).map(|(s, k)| (s.to_string(), k))
-                            )
+                            );
                         }
                     }
                 }
-                parser::Node::Expr(_, parser::Expr::Filter("skip", _)) => {
-                    let next = tokens.get(idx + 1);
-                    let result = token_expr(input.as_str(), next);
-
-                    if let Ok((rest, _)) = result {
-                        input = rest.to_string();
-                    } else {
-                        input = String::default();
+                parser::Node::Expr(_, parser::Expr::StrLit(lit), _)
+                | parser::Node::Expr(_, parser::Expr::CharLit(lit), _) => {
+                    match input.strip_prefix(unescape(lit).as_str()) {
+                        Some(rest) => {
+                            rx.trace(|| explain::Step::Match(explain::node(token), unescape(lit)));
+                            input = rest.to_string()
+                        }
+                        None => {
+                            rx.trace(|| explain::Step::Fail(explain::node(token), input.clone()));
+                            return Err(
+                                default_error(input.as_str()).map(|(s, k)| (s.to_string(), k))
+                            );
+                        }
                     }
                 }
-                parser::Node::Expr(_, parser::Expr::Var(key)) => {
-                    let next = tokens.get(idx + 1);
-                    let result = token_expr(input.as_str(), next);
-
-                    if let Ok((rest, hit)) = result {
-                        input = rest.to_string();
-                        h.insert(key.to_string(), hit);
-                    } else {
+                parser::Node::Expr(_, parser::Expr::Var(key), _) if vars.contains_key(*key) => {
+                    h.insert(key.to_string(), vars[*key].clone());
+                }
+                parser::Node::Expr(_, expr, quantifier) if Self::capture(expr).is_some() => {
+                    let capture = Self::capture(expr).unwrap_or_default();
+                    let ends = match capture.width {
+                        Some((width, display)) => {
+                            match filter::width(input.as_str(), width, display) {
+                                0 => vec![],
+                                end => vec![end],
+                            }
+                        }
+                        None => capture_ends(input.as_str(), &next, quantifier),
+                    };
+
+                    if ends.is_empty() && capture.format.is_none() {
+                        rx.trace(|| explain::Step::Match(explain::node(token), String::default()));
                         input = String::default();
+                        continue;
                     }
+
+                    for end in ends.into_iter() {
+                        let hit = if capture.trim {
+                            input[..end].trim()
+                        } else {
+                            &input[..end]
+                        };
+                        let json = match capture.json {
+                            Some(_) => match filter::json(hit) {
+                                Some(json) => Some(json),
+                                None => continue,
+                            },
+                            None => None,
+                        };
+                        let json = match (&json, capture.json) {
+                            (Some(json), Some(Some(path))) => filter::json_path(json, path),
+                            (json, _) => json.as_ref(),
+                        };
+                        let value = match (capture.json, json, capture.format) {
+                            _ if capture.csv => match filter::csv(hit) {
+                                Some(value) => Some(value),
+                                None => continue,
+                            },
+                            (Some(Some(_)), json, _) => json.map(filter::json_text),
+                            (None, _, Some(format)) => match filter::datetime(hit, format) {
+                                Some(t) => Some(t),
+                                None => continue,
+                            },
+                            _ => Some(hit.to_string()),
+                        };
+                        let pairs = match (json, capture.key) {
+                            _ if capture.kv => match filter::kv(hit) {
+                                Some(pairs) => pairs,
+                                None => continue,
+                            },
+                            (Some(json), Some(key)) if capture.flatten => {
+                                filter::flatten(json, key)
+                            }
+                            _ => Vec::default(),
+                        };
+
+                        rx.trace(|| explain::Step::Match(explain::node(token), hit.to_string()));
+
+                        let mut h = h.clone();
+                        for (key, value) in pairs.into_iter() {
+                            h.entry(key).or_insert(value);
+                        }
+                        if let (Some(key), Some(value), false) = (capture.key, value, capture.kv) {
+                            h.insert(key.to_string(), value);
+                        }
+
+                        let mark = rx.mark();
+                        let rest = input[end..].to_string();
+                        let tail = &tokens[idx + 1..];
+                        let key = rx.failure_key(tail, &rest);
+
+                        if rx.failed.contains(&key) {
+                            continue;
+                        }
+
+                        match Self::parse_tokens(rx, rest, tail, follow, vars, h, items.clone())
+                            .await
+                        {
+                            Ok(result) => return Ok(result),
+                            Err(_) => {
+                                rx.rewind(mark);
+                                rx.trace(|| explain::Step::Backtrack(explain::node(token)));
+
+                                if !Self::reads_captures(tail) {
+                                    rx.failed.insert(key);
+                                }
+                            }
+                        }
+                    }
+
+                    rx.trace(|| explain::Step::Fail(explain::node(token), input.clone()));
+                    return Err(default_error(input.as_str()).map(|(s, k)| (s.to_string(), k)));
+                }
+                parser::Node::Expr(_, parser::Expr::Filter("datetime", _), _) => {
+                    rx.trace(|| explain::Step::Fail(explain::node(token), input.clone()));
+                    return Err(default_error(input.as_str()).map(|(s, k)| (s.to_string(), k)));
                 }
                 parser::Node::Cond(exprs, _) => {
-                    for (_ws, expr, ns) in exprs.iter() {
-                        match expr {
-                            Some(parser::Expr::BinOp(op, left, right)) => {
-                                if Self::bin_op(&mut h, op, left, right) {
-                                    if let Ok((_, h2)) = Self::parse_token(rx, &input, ns).await {
-                                        for m in h2.iter() {
-                                            for (k, v) in m.iter() {
-                                                h.insert(k.to_string(), v.to_owned());
-                                            }
-                                        }
-                                    }
+                    let scope = Self::merge(vars, &h);
+                    let branch = exprs.iter().find(|(_, expr, _)| {
+                        expr.as_ref().is_none_or(|expr| eval::truthy(&scope, expr))
+                    });
+
+                    if let Some((_, _, ns)) = branch {
+                        let (rest, h2) = Self::parse_token(rx, &input, ns, &next, vars).await?;
+                        input = rest;
+
+                        for mut m in h2.into_iter() {
+                            h.append(&mut m.fields);
+                            items.append(&mut m.items);
+                        }
+                    }
+                }
+                parser::Node::Ws => {
+                    input = input.trim_start_matches(&[' ', '\t'][..]).to_string();
+                }
+                parser::Node::Optional(_, nodes, _) => {
+                    let mark = rx.mark();
+                    let joined = nodes
+                        .iter()
+                        .chain(tokens[idx + 1..].iter())
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    // `joined` is freed after this attempt, so failures recorded against it
+                    // must not outlive it.
+                    let failed = std::mem::take(&mut rx.failed);
+                    let result = Self::parse_tokens(
+                        rx,
+                        input.clone(),
+                        &joined,
+                        follow,
+                        vars,
+                        h.clone(),
+                        items.clone(),
+                    )
+                    .await;
+                    rx.failed = failed;
+
+                    match result {
+                        Ok(result) => return Ok(result),
+                        Err(_) => {
+                            rx.rewind(mark);
+                            rx.trace(|| explain::Step::Backtrack(explain::node(token)));
+                        }
+                    }
+                }
+                parser::Node::Loop(_, parser::Target::Name(name), iter, nodes, _)
+                    if Self::repeat(iter).is_some() =>
+                {
+                    let sep = Self::repeat(iter).unwrap_or_default();
+                    let sep_token = sep.map(|sep| {
+                        parser::Node::Expr(
+                            parser::WS(false, false),
+                            parser::Expr::StrLit(sep),
+                            parser::Quantifier::default(),
+                        )
+                    });
+                    let sep = sep.map(unescape);
+                    let body_follow = sep_token
+                        .iter()
+                        .chain(nodes.first())
+                        .chain(next.iter().cloned())
+                        .collect::<Vec<_>>();
+                    let mut values = Vec::default();
+
+                    loop {
+                        let mut rest = input.as_str();
+
+                        if let (Some(sep), false) = (&sep, values.is_empty()) {
+                            match rest.strip_prefix(sep) {
+                                Some(r) => rest = r,
+                                None => break,
+                            }
+                        }
+
+                        if rest.is_empty() {
+                            break;
+                        }
+
+                        match Self::parse_token(rx, rest, nodes, &body_follow, vars).await {
+                            Ok((r, rows)) if r.len() < rest.len() && !rows.is_empty() => {
+                                for mut row in rows.into_iter() {
+                                    let mut item = row.fields.clone();
+                                    item.insert(String::from("idx"), values.len().to_string());
+                                    items.push(item);
+                                    items.append(&mut row.items);
+                                    values.push(row.fields);
                                 }
+                                input = r;
                             }
-                            _ => {}
+                            _ => break,
                         }
                     }
+
+                    h.insert(name.to_string(), Self::repeat_value(values));
                 }
-                parser::Node::Loop(_, _, parser::Expr::Range("..", Some(s), Some(e)), nodes, _) => {
-                    let s: u32 = Self::get_variable(&mut h, s).unwrap_or_default();
-                    let e: u32 = Self::get_variable(&mut h, e).unwrap_or_default();
-
-                    for n in s..e {
-                        if let Ok((_, h2)) = Self::parse_token(rx, &input, nodes).await {
-                            for m in h2.iter() {
-                                for (k, v) in m.iter() {
-                                    h.insert(format!("i{}_{}", n, k), v.to_owned());
+                parser::Node::Loop(_, target, parser::Expr::Range(op, s, e), nodes, _) => {
+                    let scope = Self::merge(vars, &h);
+                    let s = match s {
+                        Some(s) => eval::int(&scope, s),
+                        None => Some(0),
+                    };
+                    let e = match e {
+                        Some(e) => eval::int(&scope, e)
+                            .map(|e| if *op == "..=" { e + 1 } else { e })
+                            .map(Some),
+                        None => Some(None),
+                    };
+
+                    if let (Some(s), Some(e)) = (s, e) {
+                        for (count, n) in (s..).take_while(|n| e.is_none_or(|e| *n < e)).enumerate()
+                        {
+                            if input.is_empty() {
+                                input = Self::read_line(rx).await;
+                            }
+
+                            if input.is_empty() {
+                                break;
+                            }
+
+                            let mut scope = vars.clone();
+                            match target {
+                                parser::Target::Name(name) => {
+                                    scope.insert(name.to_string(), n.to_string());
+                                }
+                                parser::Target::Tuple(names) => {
+                                    let values = [count as i64, n];
+                                    for (name, value) in names.iter().zip(values.iter()) {
+                                        scope.insert(name.to_string(), value.to_string());
+                                    }
                                 }
                             }
-                        }
 
-                        input = Self::read_line(rx).await
+                            match Self::parse_token(rx, &input, nodes, &next, &scope).await {
+                                Ok((_, h2)) => {
+                                    let mut row = Record::default();
+                                    for mut m in h2.into_iter() {
+                                        row.fields.append(&mut m.fields);
+                                        row.items.append(&mut m.items);
+                                    }
+
+                                    let mut item = Self::merge(&scope, &row.fields);
+                                    item.insert(String::from("idx"), count.to_string());
+                                    items.push(item);
+                                    items.append(&mut row.items);
+                                }
+                                Err(_) if e.is_none() => break,
+                                Err(_) => {}
+                            }
+
+                            input = String::default();
+                        }
                     }
                 }
 
@@ -192,47 +701,123 @@ impl TokenExpr {
             }
         }
 
-        if h.is_empty() {
-            IResult::Ok((String::default(), Vec::default()))
+        if h.is_empty() && items.is_empty() {
+            IResult::Ok((input, Vec::default()))
         } else {
-            IResult::Ok((String::default(), vec![h]))
+            IResult::Ok((input, vec![Record { fields: h, items }]))
         }
     }
+}
 
-    fn bin_op(
-        h: &mut BTreeMap<String, String>,
-        op: &str,
-        left: &parser::Expr,
-        right: &parser::Expr,
-    ) -> bool {
-        match op {
-            "==" => {
-                Self::get_variable::<String>(&h, left) == Self::get_variable::<String>(&h, right)
-            }
-            "!=" => {
-                Self::get_variable::<String>(&h, left) != Self::get_variable::<String>(&h, right)
-            }
-            ">" => Self::get_variable::<f64>(&h, left) > Self::get_variable::<f64>(&h, right),
-            "<" => Self::get_variable::<f64>(&h, left) < Self::get_variable::<f64>(&h, right),
-            ">=" => Self::get_variable::<f64>(&h, left) >= Self::get_variable::<f64>(&h, right),
-            "<=" => Self::get_variable::<f64>(&h, left) <= Self::get_variable::<f64>(&h, right),
-            _ => false,
+type Line = (String, Option<record::Position>);
+
+pub struct LineBuffer<'r> {
+    rx: &'r mut mpsc::UnboundedReceiver<InputToken>,
+    pending: VecDeque<Line>,
+    taken: Vec<Line>,
+    committed: Vec<Line>,
+    eof: bool,
+    continuation: bool,
+    trace: Option<Vec<explain::Step>>,
+    failed: HashSet<(usize, usize, usize, usize)>,
+}
+
+impl<'r> LineBuffer<'r> {
+    pub fn new(rx: &'r mut mpsc::UnboundedReceiver<InputToken>) -> Self {
+        LineBuffer {
+            rx,
+            pending: VecDeque::default(),
+            taken: Vec::default(),
+            committed: Vec::default(),
+            eof: false,
+            continuation: true,
+            trace: None,
+            failed: HashSet::default(),
         }
     }
 
-    fn get_variable<F>(h: &BTreeMap<String, String>, expr: &parser::Expr) -> Option<F>
-    where
-        F: std::str::FromStr + PartialOrd,
-        F::Err: std::fmt::Debug,
-    {
-        let a = match expr {
-            parser::Expr::Var(n) => h.get(*n).map(String::to_string),
-            parser::Expr::NumLit(num) => Some(num.to_string()),
-            parser::Expr::StrLit(s) => Some(s.to_string()),
-            _ => None,
+    pub fn explain(mut self, explain: bool) -> Self {
+        self.trace = if explain { Some(Vec::default()) } else { None };
+        self
+    }
+
+    fn trace<F: FnOnce() -> explain::Step>(&mut self, step: F) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(step());
+        }
+    }
+
+    fn take_trace(&mut self) -> Vec<explain::Step> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn continuation(mut self, continuation: bool) -> Self {
+        self.continuation = continuation;
+        self
+    }
+
+    async fn next_continuation(&mut self) -> Option<String> {
+        if self.continuation {
+            self.next().await
+        } else {
+            None
+        }
+    }
+
+    pub async fn next(&mut self) -> Option<String> {
+        let line = match self.pending.pop_front() {
+            Some(line) => line,
+            None if self.eof => return None,
+            None => match self.rx.recv().await {
+                Some(InputToken::Channel(line)) => (line, None),
+                Some(InputToken::Record(line, position)) => (line, Some(position)),
+                _ => {
+                    self.eof = true;
+                    return None;
+                }
+            },
         };
 
-        a.map(|v| v.parse::<F>().unwrap())
+        self.taken.push(line.clone());
+        Some(line.0)
+    }
+
+    pub fn commit(&mut self) {
+        self.committed = std::mem::take(&mut self.taken);
+    }
+
+    fn committed(&self) -> (Option<record::Position>, String) {
+        let position = self.committed.first().and_then(|(_, position)| *position);
+        let raw = self
+            .committed
+            .iter()
+            .map(|(line, _)| line.as_str())
+            .collect::<String>();
+
+        (position, raw)
+    }
+
+    pub fn rollback(&mut self) {
+        self.rewind(0);
+    }
+
+    fn mark(&self) -> usize {
+        self.taken.len()
+    }
+
+    fn failure_key(&self, tokens: &[parser::Node], input: &str) -> (usize, usize, usize, usize) {
+        (
+            tokens.as_ptr() as usize,
+            tokens.len(),
+            self.mark(),
+            input.len(),
+        )
+    }
+
+    fn rewind(&mut self, mark: usize) {
+        for line in self.taken.drain(mark..).rev() {
+            self.pending.push_front(line);
+        }
     }
 }
 
@@ -246,13 +831,22 @@ pub enum Output {
 pub enum InputToken {
     Byte(u8),
     Channel(String),
+    Record(String, record::Position),
     EOF,
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct AppConfig {
     pub templates: Vec<Token>,
-    pub timestamp: Option<String>,
+    pub template_column: bool,
+    pub search: bool,
+    pub explain: bool,
+    pub input_format: Option<crate::InputFormat>,
+    pub encoding: Option<String>,
+    pub metadata: Vec<crate::Metadata>,
+    pub file: Option<String>,
+    pub record: record::RecordMode,
+    pub ingest_time: Option<db::IngestTime>,
     pub table: Option<String>,
     output: Option<Output>,
     vars: Option<Vec<String>>,
@@ -268,29 +862,71 @@ fn default_error(input: &str) -> nom::Err<(&str, nom::error::ErrorKind)> {
     make_error(input, nom::error::ErrorKind::Eof)
 }
 
-fn token_expr<'a>(input: &'a str, token: Option<&parser::Node>) -> IResult<&'a str, String> {
-    let result: IResult<&str, &str> = if let Some(parser::Node::Lit(a, b, c)) = token {
-        let mut result: IResult<&str, &str> = Err(default_error(input));
-        let mut idx = 0usize;
+fn unescape(lit: &str) -> String {
+    let mut res = String::with_capacity(lit.len());
+    let mut chars = lit.chars();
 
-        for ch in input.chars().into_iter() {
-            idx += ch.len_utf8();
-
-            let s = &input[idx..];
-            let t: IResult<&str, &str> = alt((tag("\n"), tag(&format!("{}{}{}", a, b, c)[..])))(s);
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
 
-            if let Ok(_) = t {
-                result = Ok((&input[idx..input.len()], &input[0..idx]));
-                break;
-            }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some(c) => res.push(c),
+            None => res.push(c),
         }
+    }
+
+    res
+}
 
-        result
-    } else {
-        take_until("\n")(input)
-    };
+fn capture_ends(
+    input: &str,
+    tokens: &[&parser::Node],
+    quantifier: &parser::Quantifier,
+) -> Vec<usize> {
+    if tokens.is_empty() {
+        let end = input.strip_suffix('\n').unwrap_or(input).len();
+        return if end == 0 { vec![] } else { vec![end] };
+    }
+
+    let lits = tokens
+        .iter()
+        .filter_map(|token| match token {
+            parser::Node::Lit(a, b, c) => Some(format!("{}{}{}", a, b, c)),
+            parser::Node::Expr(_, parser::Expr::StrLit(lit), _)
+            | parser::Node::Expr(_, parser::Expr::CharLit(lit), _) => Some(unescape(lit)),
+            _ => None,
+        })
+        .filter(|lit| !lit.is_empty())
+        .collect::<Vec<_>>();
+    let ws = tokens.iter().any(|token| **token == parser::Node::Ws);
+
+    let limit = input.find('\n').unwrap_or(input.len());
+    let mut ends = input
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .take_while(|idx| *idx < limit)
+        .filter(|idx| {
+            (ws && *idx > 0 && input[*idx..].starts_with(char::is_whitespace))
+                || lits
+                    .iter()
+                    .any(|lit| input[*idx..].starts_with(lit.as_str()))
+        })
+        .collect::<Vec<_>>();
+
+    if limit > 0 && ends.last() != Some(&limit) {
+        ends.push(limit);
+    }
 
-    result.map(|(a, b)| (a, b.to_string()))
+    if let parser::Quantifier::Greedy = quantifier {
+        ends.reverse();
+    }
+
+    ends
 }
 
 #[allow(dead_code)]
@@ -334,75 +970,333 @@ impl App {
         Ok(())
     }
 
+    pub fn send_record(&self, txt: String, position: record::Position) -> anyhow::Result<()> {
+        self.tx.send(InputToken::Record(txt, position))?;
+        Ok(())
+    }
+
     pub fn table_name(&self) -> String {
         self.config.table.clone().unwrap_or(String::from("kaeshi"))
     }
 
+    pub fn items_table_name(&self) -> String {
+        format!("{}_items", self.table_name())
+    }
+
     pub async fn parse_handler(
         &self,
         rx: &mut mpsc::UnboundedReceiver<InputToken>,
         templates: Vec<TokenExpr>,
     ) -> anyhow::Result<()> {
         let syn = parser::Syntax::default();
-        let mut rows: Vec<BTreeMap<String, String>> = Vec::default();
+        let mut records: Vec<Record> = Vec::default();
+        let mut types = templates
+            .iter()
+            .fold(BTreeMap::default(), |mut acc, template| {
+                acc.append(&mut template.column_types(&syn));
+                acc
+            });
+        let has_items = templates.iter().any(|template| template.has_loop(&syn));
+        let has_dynamic_columns = self.config.input_format.is_some()
+            || templates
+                .iter()
+                .any(|template| template.has_dynamic_columns(&syn));
+        let mut decoder = self.config.input_format.map(input::Decoder::new);
+        let nodes = templates
+            .iter()
+            .map(|template| template.nodes(&syn))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let prefixes = nodes
+            .iter()
+            .map(|tokens| TokenExpr::prefix(tokens))
+            .collect::<Vec<_>>();
+
+        let mut lines = LineBuffer::new(rx)
+            .continuation(
+                !self.config.search && matches!(self.config.record, record::RecordMode::Line),
+            )
+            .explain(self.config.explain);
+        let mut n = 0;
 
         'main: loop {
-            for template in templates.iter() {
-                let (is_break, mut row) = template.evaluate(rx, &syn).await;
-                rows.append(&mut row);
+            if let Some(decoder) = decoder.as_mut() {
+                match lines.next().await {
+                    Some(mut text) => {
+                        while decoder.incomplete(&text) {
+                            match lines.next().await {
+                                Some(more) => text.push_str(&more),
+                                None => break,
+                            }
+                        }
+
+                        lines.commit();
+                        if let Some(fields) = decoder.decode(&text) {
+                            let mut record = vec![Record {
+                                fields,
+                                ..Record::default()
+                            }];
+                            self.metadata_columns(&lines, &mut record);
+                            records.append(&mut record);
+                        }
+                        continue 'main;
+                    }
+                    None => break,
+                }
+            }
 
-                if is_break {
-                    break 'main;
+            if self.config.search {
+                match lines.next().await {
+                    Some(text) => {
+                        let mut record = self
+                            .search(&mut lines, &text, &templates, &nodes, &prefixes)
+                            .await;
+                        lines.commit();
+                        self.metadata_columns(&lines, &mut record);
+                        records.append(&mut record);
+                        continue 'main;
+                    }
+                    None => break,
                 }
             }
+
+            let text = if self.config.explain {
+                let text = match lines.next().await {
+                    Some(text) => text,
+                    None => break,
+                };
+                lines.rollback();
+                n += 1;
+                Some(text)
+            } else {
+                None
+            };
+            let mut attempts = Vec::default();
+
+            for (idx, template) in templates.iter().enumerate() {
+                let record = TokenExpr::evaluate(&mut lines, &nodes[idx]).await;
+
+                if let Some(text) = &text {
+                    attempts.push(explain::Attempt {
+                        template: Self::template_name(idx, template),
+                        steps: lines.take_trace(),
+                        matched: record.is_some(),
+                    });
+
+                    if record.is_some() || idx + 1 == templates.len() {
+                        eprint!("{}", explain::report(n, text, &attempts));
+                    }
+                }
+
+                if let Some(mut record) = record {
+                    self.template_column(idx, template, &mut record);
+                    self.metadata_columns(&lines, &mut record);
+                    records.append(&mut record);
+                    continue 'main;
+                }
+            }
+
+            if lines.next().await.is_none() {
+                break;
+            }
+            lines.commit();
         }
 
-        let titles = rows
-            .iter()
-            .fold(HashSet::<String>::default(), |acc, row| {
-                let ks: HashSet<String> = row.keys().cloned().collect();
+        let mut rows: DB = Vec::default();
+        let mut items: DB = Vec::default();
 
-                acc.union(&ks).cloned().collect::<HashSet<String>>()
-            })
-            .into_iter()
-            .collect::<Vec<_>>();
+        for (id, record) in records.into_iter().enumerate() {
+            let mut row = record.fields;
 
-        self.db
-            .borrow_mut()
-            .create_table(
-                self.config.table.clone(),
-                titles,
-                self.config.timestamp.clone(),
-            )
-            .await?;
+            if has_items {
+                let parent_id = (id + 1).to_string();
+                row.insert(String::from("_id"), parent_id.clone());
+
+                for mut item in record.items.into_iter() {
+                    item.insert(String::from("parent_id"), parent_id.clone());
+                    items.push(item);
+                }
+            }
+
+            rows.push(row);
+        }
+
+        if has_items {
+            for key in ["_id", "parent_id", "idx"].iter() {
+                types.insert(key.to_string(), db::ColumnType::Int);
+            }
+        }
+
+        for metadata in self.config.metadata.iter() {
+            types.insert(metadata.column(), metadata.column_type());
+        }
+
+        if has_dynamic_columns {
+            for key in rows.iter().chain(items.iter()).flat_map(|row| row.keys()) {
+                types
+                    .entry(key.clone())
+                    .or_insert(db::ColumnType::NullableText);
+            }
+        }
+
+        let sql = self.db.borrow_mut().create_table_sql(
+            self.config.table.clone(),
+            Self::columns(&rows, &types),
+            self.config.ingest_time.clone(),
+        );
+        self.execute(&sql).await?;
 
         for row in rows.iter() {
-            self.db.borrow_mut().insert(row).await?;
+            let sql = self.db.borrow().insert_sql(&self.table_name(), row)?;
+            self.execute(&sql).await?;
+        }
+
+        if has_items {
+            let mut columns = Self::columns(&items, &types);
+            for key in ["parent_id", "idx"].iter() {
+                if !columns.iter().any(|(c, _)| c == key) {
+                    columns.push((key.to_string(), db::ColumnType::Int));
+                }
+            }
+
+            let sql =
+                self.db
+                    .borrow_mut()
+                    .create_table_sql(Some(self.items_table_name()), columns, None);
+            self.execute(&sql).await?;
+
+            for item in items.iter() {
+                let sql = self
+                    .db
+                    .borrow()
+                    .insert_sql(&self.items_table_name(), item)?;
+                self.execute(&sql).await?;
+            }
         }
 
         Ok(())
     }
 
+    async fn search(
+        &self,
+        lines: &mut LineBuffer<'_>,
+        text: &str,
+        templates: &[TokenExpr],
+        nodes: &[Vec<parser::Node<'_>>],
+        prefixes: &[String],
+    ) -> Vec<Record> {
+        let mut records = Vec::default();
+        let mut offset = 0;
+
+        'search: while offset < text.len() {
+            let rest = &text[offset..];
+
+            for (n, template) in templates.iter().enumerate() {
+                if !rest.starts_with(prefixes[n].as_str()) {
+                    continue;
+                }
+
+                match TokenExpr::parse(lines, rest, &nodes[n]).await {
+                    Ok((r, mut record)) if r.len() < rest.len() && !record.is_empty() => {
+                        self.template_column(n, template, &mut record);
+                        records.append(&mut record);
+                        offset = text.len() - r.len();
+                        continue 'search;
+                    }
+                    _ => {}
+                }
+            }
+
+            offset += rest.chars().next().map_or(1, char::len_utf8);
+        }
+
+        records
+    }
+
+    fn template_name(n: usize, template: &TokenExpr) -> String {
+        template.name.clone().unwrap_or_else(|| n.to_string())
+    }
+
+    fn template_column(&self, n: usize, template: &TokenExpr, records: &mut [Record]) {
+        if self.config.template_column {
+            let name = Self::template_name(n, template);
+            for r in records.iter_mut() {
+                r.fields.insert(String::from("_template"), name.clone());
+            }
+        }
+    }
+
+    fn metadata_columns(&self, lines: &LineBuffer<'_>, records: &mut [Record]) {
+        if self.config.metadata.is_empty() {
+            return;
+        }
+
+        let (position, raw) = lines.committed();
+
+        for metadata in self.config.metadata.iter() {
+            let value = match metadata {
+                crate::Metadata::Line => position.map(|p| p.line.to_string()),
+                crate::Metadata::Offset => position.map(|p| p.offset.to_string()),
+                crate::Metadata::File => Some(
+                    self.config
+                        .file
+                        .clone()
+                        .unwrap_or_else(|| String::from("-")),
+                ),
+                crate::Metadata::Raw => Some(raw.strip_suffix('\n').unwrap_or(&raw).to_string()),
+            };
+
+            if let Some(value) = value {
+                for r in records.iter_mut() {
+                    r.fields.insert(metadata.column(), value.clone());
+                }
+            }
+        }
+    }
+
+    fn columns(
+        rows: &DB,
+        types: &BTreeMap<String, db::ColumnType>,
+    ) -> Vec<(String, db::ColumnType)> {
+        rows.iter()
+            .fold(HashSet::<String>::default(), |acc, row| {
+                let ks: HashSet<String> = row.keys().cloned().collect();
+
+                acc.union(&ks).cloned().collect::<HashSet<String>>()
+            })
+            .into_iter()
+            .map(|title| {
+                let t = types.get(&title).cloned().unwrap_or_default();
+                (title, t)
+            })
+            .collect::<Vec<_>>()
+    }
+
     pub async fn input_handler(&self) -> anyhow::Result<()> {
-        let stdin = std::io::stdin();
+        self.read_handler(std::io::stdin().lock()).await
+    }
+
+    pub async fn read_handler<R: BufRead>(&self, reader: R) -> anyhow::Result<()> {
+        let mut reader = encoding::LineReader::new(reader, self.config.encoding.as_deref())?;
+        let mut assembler = record::Assembler::new(&self.config.record)?;
 
         loop {
-            let mut buf = Vec::with_capacity(1024usize);
-
-            match stdin.lock().read_until(b'\n', &mut buf) {
-                Ok(n) => {
-                    let line = String::from_utf8_lossy(&buf).to_string();
-                    if n == 0 {
-                        self.send_byte(b'\0')?;
-                        break;
+            match reader.next_line() {
+                Ok(Some((line, len))) => {
+                    if let Some((record, position)) = assembler.push(line, len) {
+                        self.send_record(record, position)?;
                     }
-
-                    self.send_string(line.to_string())?;
                 }
-                Err(e) => {
-                    error!("{}", e.to_string());
+                Ok(None) => {
+                    if let Some((record, position)) = assembler.finish() {
+                        self.send_record(record, position)?;
+                    }
+                    self.send_byte(b'\0')?;
                     break;
                 }
+                Err(e) => {
+                    self.send_byte(b'\0')?;
+                    return Err(e.into());
+                }
             }
         }
 
@@ -413,3 +1307,693 @@ impl App {
         self.db.borrow_mut().execute(sql).await
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use futures_await_test::async_test;
+    use gluesql::{data::Value, Payload};
+
+    pub(crate) async fn select(templates: &[&str], lines: &[&str], sql: &str) -> Vec<Vec<Value>> {
+        select_with_config(AppConfig::default(), templates, lines, sql).await
+    }
+
+    pub(crate) async fn select_with_config(
+        config: AppConfig,
+        templates: &[&str],
+        lines: &[&str],
+        sql: &str,
+    ) -> Vec<Vec<Value>> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let templates = templates
+            .iter()
+            .map(|t| TokenExpr::new_with_tag(&t.to_string()))
+            .collect::<Vec<_>>();
+        let app = App::new_with_config(tx, config).await.unwrap();
+
+        for line in lines {
+            app.send_string(format!("{}\n", line)).unwrap();
+        }
+        app.send_byte(b'\0').unwrap();
+
+        app.parse_handler(&mut rx, templates).await.unwrap();
+
+        rows(&app, sql).await
+    }
+
+    async fn rows(app: &App, sql: &str) -> Vec<Vec<Value>> {
+        match app.execute(sql).await {
+            Ok(Some(Payload::Select { rows, .. })) => rows,
+            n => panic!("{:?}", n),
+        }
+    }
+
+    async fn load(config: AppConfig, templates: &[&str], input: &[u8], prev: Option<App>) -> App {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let templates = templates
+            .iter()
+            .map(|t| TokenExpr::new_with_tag(&t.to_string()))
+            .collect::<Vec<_>>();
+        let app = App::new_with_config(tx, config).await.unwrap();
+        if let Some(prev) = prev {
+            app.db.swap(&prev.db);
+        }
+
+        let (read, parse) = futures::join!(
+            app.read_handler(std::io::Cursor::new(input)),
+            app.parse_handler(&mut rx, templates)
+        );
+        read.unwrap();
+        parse.unwrap();
+        app
+    }
+
+    async fn select_from_reader(
+        config: AppConfig,
+        templates: &[&str],
+        input: &[u8],
+        sql: &str,
+    ) -> Vec<Vec<Value>> {
+        rows(&load(config, templates, input, None).await, sql).await
+    }
+
+    #[async_test]
+    async fn it_repeat() {
+        let rows = select(
+            &[r#"id={{ id }} tags={% for tags in repeat|sep(",") %}{{ tag }}{% endfor %}"#],
+            &["id=1 tags=a,b,c", "id=2 tags="],
+            "SELECT tags FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(
+            vec![
+                vec![Value::Str(r#"["a","b","c"]"#.to_string())],
+                vec![Value::Str("[]".to_string())],
+            ],
+            rows
+        );
+
+        let rows = select(
+            &[r#"{% for values in repeat|sep("\t") %}{{ v }}{% endfor %}"#],
+            &["a\tb"],
+            "SELECT values FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(vec![vec![Value::Str(r#"["a","b"]"#.to_string())]], rows);
+    }
+
+    #[async_test]
+    async fn it_loop_items() {
+        let rows = select(
+            &["head {{ name }}\n{% for i in 0..2 %}v {{ v }}\n{% endfor %}"],
+            &["head h1", "v 1", "v 2", "head h2", "v 3", "v 4"],
+            "SELECT k.name, i.idx, i.v FROM kaeshi k JOIN kaeshi_items i ON k._id = i.parent_id",
+        )
+        .await;
+
+        let row = |name: &str, idx: i64, v: &str| {
+            vec![
+                Value::Str(name.to_string()),
+                Value::I64(idx),
+                Value::Str(v.to_string()),
+            ]
+        };
+
+        assert_eq!(
+            vec![
+                row("h1", 0, "1"),
+                row("h1", 1, "2"),
+                row("h2", 0, "3"),
+                row("h2", 1, "4"),
+            ],
+            rows
+        );
+    }
+
+    #[async_test]
+    async fn it_loop_range() {
+        let rows = select(
+            &["n={{ n }}\n{% for i in 1..=n-1 %}v {{ v }}\n{% endfor %}"],
+            &["n=3", "v a", "v b", "n=2", "v c"],
+            "SELECT parent_id, i, v FROM kaeshi_items",
+        )
+        .await;
+
+        let row = |parent_id: i64, i: i64, v: &str| {
+            vec![
+                Value::I64(parent_id),
+                Value::I64(i),
+                Value::Str(v.to_string()),
+            ]
+        };
+
+        assert_eq!(vec![row(1, 1, "a"), row(1, 2, "b"), row(2, 1, "c")], rows);
+
+        let rows = select(
+            &["begin {{ b }}\n{% for (k, n) in 10.. %}- {{ x }}\n{% endfor %}end {{ e }}"],
+            &["begin 1", "- a", "- b", "end z"],
+            "SELECT k, n, x FROM kaeshi_items",
+        )
+        .await;
+
+        let row =
+            |k: i64, n: i64, x: &str| vec![Value::I64(k), Value::I64(n), Value::Str(x.to_string())];
+
+        assert_eq!(vec![row(0, 10, "a"), row(1, 11, "b")], rows);
+    }
+
+    #[async_test]
+    async fn it_first_matching_template() {
+        let rows = select(
+            &["GET {{ path }} {{ code }}", "ERROR {{ msg }}"],
+            &["GET /a 200", "ERROR boom", "noise", "GET /b 404"],
+            "SELECT path, msg FROM kaeshi",
+        )
+        .await;
+
+        let row =
+            |path: &str, msg: &str| vec![Value::Str(path.to_string()), Value::Str(msg.to_string())];
+
+        assert_eq!(vec![row("/a", ""), row("", "boom"), row("/b", "")], rows);
+    }
+
+    #[async_test]
+    async fn it_search() {
+        let config = AppConfig {
+            search: true,
+            ..AppConfig::default()
+        };
+        let rows = select_with_config(
+            config,
+            &[r#"<a href="{{ href }}">"#],
+            &[r#"<p><a href="/x">X</a> <a href="/y">Y</a></p>"#, "none"],
+            "SELECT href FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(
+            vec![
+                vec![Value::Str(String::from("/x"))],
+                vec![Value::Str(String::from("/y"))]
+            ],
+            rows
+        );
+    }
+
+    #[async_test]
+    async fn it_optional() {
+        let rows = select(
+            &["{{ prog }}{% optional %}[{{ pid }}]{% endoptional %}: {{ msg }}"],
+            &["sshd[12]: hello", "cron: tick"],
+            "SELECT prog, pid, msg FROM kaeshi",
+        )
+        .await;
+
+        let s = |s: &str| Value::Str(s.to_string());
+
+        assert_eq!(vec![s("sshd"), s("12"), s("hello")], rows[0]);
+        assert_eq!(s("cron"), rows[1][0]);
+        assert!(matches!(rows[1][1], Value::Null));
+        assert_eq!(s("tick"), rows[1][2]);
+    }
+
+    #[async_test]
+    async fn it_optional_backtrack() {
+        let rows = select(
+            &[r#"{% optional %}{{ a }} {% endoptional %}{{ t|datetime("%Y-%m-%d") }}"#],
+            &["x y 2021-09-10"],
+            "SELECT a FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(vec![vec![Value::Str("x y".to_string())]], rows);
+    }
+
+    #[async_test]
+    async fn it_backtrack() {
+        let rows = select(
+            &["{{ a }} - {{ b }} - end"],
+            &["x - y - z - end"],
+            "SELECT a, b FROM kaeshi",
+        )
+        .await;
+
+        let s = |s: &str| Value::Str(s.to_string());
+
+        assert_eq!(vec![vec![s("x"), s("y - z")]], rows);
+
+        let rows = select(
+            &["{{ a+ }} - {{ b }}"],
+            &["x - y - z"],
+            "SELECT a, b FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(vec![vec![s("x - y"), s("z")]], rows);
+    }
+
+    #[async_test]
+    async fn it_empty_capture() {
+        let rows = select(
+            &["{{ a }},{{ b }},{{ c }}"],
+            &["x,,z"],
+            "SELECT a, b, c FROM kaeshi",
+        )
+        .await;
+
+        let s = |s: &str| Value::Str(s.to_string());
+
+        assert_eq!(vec![vec![s("x"), s(""), s("z")]], rows);
+    }
+    #[async_test]
+    async fn it_backtrack_failing_tail() {
+        let template = (0..8)
+            .map(|n| format!("{{{{ c{} }}}}", n))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let words = (0..40).map(|n| format!("w{}", n)).collect::<Vec<_>>();
+        let failing = words.join(" ");
+        let matching = format!("{} END", failing);
+        let rows = select(
+            &[&format!("{} END", template)],
+            &[&failing, &matching],
+            "SELECT c0, c7 FROM kaeshi",
+        )
+        .await;
+
+        let s = |s: &str| Value::Str(s.to_string());
+
+        assert_eq!(vec![vec![s("w0"), s(&words[7..].join(" "))]], rows);
+    }
+
+    #[async_test]
+    async fn it_whitespace_control() {
+        let rows = select(
+            &["{{ user }} {{- pid }} {{- cmd }}"],
+            &["root         1 /sbin/init", "www-data   123 nginx: worker"],
+            "SELECT user, pid, cmd FROM kaeshi",
+        )
+        .await;
+
+        let s = |s: &str| Value::Str(s.to_string());
+
+        assert_eq!(
+            vec![
+                vec![s("root"), s("1"), s("/sbin/init")],
+                vec![s("www-data"), s("123"), s("nginx: worker")]
+            ],
+            rows
+        );
+    }
+
+    #[async_test]
+    async fn it_whitespace_control_within_line() {
+        let rows = select(
+            &["{{ user }} {{- pid }}"],
+            &["root", "www 12"],
+            "SELECT user, pid FROM kaeshi",
+        )
+        .await;
+
+        let s = |s: &str| Value::Str(s.to_string());
+
+        assert_eq!(vec![vec![s("root"), s("")], vec![s("www"), s("12")]], rows);
+    }
+
+    #[async_test]
+    async fn it_fixed_width() {
+        let s = |s: &str| Value::Str(s.to_string());
+        let rows = select(
+            &["{{ name|width(6)|trim }}{{ id|width(3) }}{{ city }}"],
+            &["alice 001Tokyo", "太郎    002Osaka"],
+            "SELECT name, id, city FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(
+            vec![
+                vec![s("alice"), s("001"), s("Tokyo")],
+                vec![s("太郎"), s("002"), s("Osaka")]
+            ],
+            rows
+        );
+
+        let rows = select(
+            &["{{ name|display_width(6)|trim }}{{ id|width(3) }}{{ city }}"],
+            &["alice 001Tokyo", "太郎  002Osaka"],
+            "SELECT name, id, city FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(
+            vec![
+                vec![s("alice"), s("001"), s("Tokyo")],
+                vec![s("太郎"), s("002"), s("Osaka")]
+            ],
+            rows
+        );
+    }
+
+    #[async_test]
+    async fn it_raw() {
+        let rows = select(
+            &[
+                "{% raw %}{{ .Values.{% endraw %}{{ key }} }}",
+                r#"{{ "{%" }} {{ tag }} {{ "%}" }}"#,
+            ],
+            &["{{ .Values.image }}", "{% if %}"],
+            "SELECT key, tag FROM kaeshi",
+        )
+        .await;
+
+        let row =
+            |key: &str, tag: &str| vec![Value::Str(key.to_string()), Value::Str(tag.to_string())];
+
+        assert_eq!(vec![row("image", ""), row("", "if")], rows);
+    }
+
+    #[async_test]
+    async fn it_kv() {
+        let rows = select(
+            &["{{ time }} {{ rest|kv }}"],
+            &[
+                r#"12:00 level=info msg="user logged in" user=alice"#,
+                r#"12:01 level=error msg="disk full" time=ignored"#,
+            ],
+            "SELECT time, level, msg, user FROM kaeshi",
+        )
+        .await;
+        let s = |s: &str| Value::Str(s.to_string());
+
+        assert_eq!(
+            vec![s("12:00"), s("info"), s("user logged in"), s("alice")],
+            rows[0]
+        );
+        assert_eq!(vec![s("12:01"), s("error"), s("disk full")], rows[1][..3]);
+        assert!(matches!(rows[1][3], Value::Null));
+
+        let rows = select(
+            &[r#"[{{ pairs|kv }}] {{ tail }}"#],
+            &[r#"[a=1 b="x] y"] end"#],
+            "SELECT a, b, tail FROM kaeshi",
+        )
+        .await;
+        assert_eq!(vec![vec![s("1"), s("x] y"), s("end")]], rows);
+    }
+
+    #[async_test]
+    async fn it_json() {
+        let rows = select(
+            &[
+                r#"{{ level }} {{ payload|json }} {{ took }}"#,
+                r#"{{ level }} {{ user|json("$.user.id") }}"#,
+            ],
+            &[
+                r#"info {"msg": "a } b"} 12ms"#,
+                r#"warn {"user": {"id": 7}}"#,
+                r#"warn {"user": }"#,
+            ],
+            "SELECT level, payload, took, user FROM kaeshi",
+        )
+        .await;
+        let s = |s: &str| Value::Str(s.to_string());
+
+        assert_eq!(2, rows.len());
+        assert_eq!(
+            vec![s("info"), s(r#"{"msg": "a } b"}"#), s("12ms")],
+            rows[0][..3]
+        );
+        assert_eq!(vec![s("warn"), s(""), s(""), s("7")], rows[1]);
+
+        let rows = select(
+            &[r#"{{ p|json|flatten }}"#],
+            &[
+                r#"{"user": {"id": 7, "name": "alice"}}"#,
+                r#"{"level": "warn"}"#,
+            ],
+            "SELECT p_user_name, p_level FROM kaeshi",
+        )
+        .await;
+        assert_eq!(s("alice"), rows[0][0]);
+        assert!(matches!(rows[0][1], Value::Null));
+        assert_eq!(s("warn"), rows[1][1]);
+    }
+
+    #[async_test]
+    async fn it_input_format() {
+        let config = AppConfig {
+            input_format: Some(crate::InputFormat::Ndjson),
+            ..AppConfig::default()
+        };
+        let rows = select_with_config(
+            config,
+            &[],
+            &[r#"{"level": "info", "took": 12}"#, r#"{"level": "warn"}"#],
+            "SELECT level, took FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(Value::Str("info".to_string()), rows[0][0]);
+        assert_eq!(Value::Str("12".to_string()), rows[0][1]);
+        assert!(matches!(rows[1][1], Value::Null));
+    }
+
+    #[async_test]
+    async fn it_read_error() {
+        struct Broken;
+
+        impl std::io::Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(std::io::ErrorKind::Other, "broken"))
+            }
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let templates = vec![TokenExpr::new_with_tag(&String::from("{{ x }}"))];
+        let app = App::new_with_config(tx, AppConfig::default())
+            .await
+            .unwrap();
+        let (read, parse) = futures::join!(
+            app.read_handler(std::io::BufReader::new(Broken)),
+            app.parse_handler(&mut rx, templates)
+        );
+
+        assert!(read.is_err());
+        assert!(parse.is_ok());
+    }
+
+    #[async_test]
+    async fn it_join_inputs() {
+        let table = |table: &str| AppConfig {
+            table: Some(table.to_string()),
+            ..AppConfig::default()
+        };
+        let users = load(
+            table("users"),
+            &["{{ id }} {{ name }}"],
+            b"1 alice\n2 bob\n",
+            None,
+        )
+        .await;
+        let logins = load(
+            table("logins"),
+            &["login {{ user_id }}"],
+            b"login 2\nlogin 2\n",
+            Some(users),
+        )
+        .await;
+
+        assert_eq!(
+            vec![vec![Value::Str("bob".to_string()), Value::I64(2)]],
+            rows(
+                &logins,
+                "SELECT users.name, COUNT(*) FROM users JOIN logins ON users.id = logins.user_id GROUP BY users.name"
+            )
+            .await
+        );
+    }
+
+    #[async_test]
+    async fn it_encoding() {
+        let text = "名前 = 太郎\n名前 = 花子\n";
+
+        for (label, input, offset) in [
+            (
+                "shift_jis",
+                encoding::encode(text, "shift_jis").unwrap(),
+                12,
+            ),
+            (
+                "auto",
+                [
+                    &[0xff, 0xfe][..],
+                    &encoding::encode(text, "utf-16le").unwrap(),
+                ]
+                .concat(),
+                18,
+            ),
+        ]
+        .iter()
+        {
+            let config = AppConfig {
+                encoding: Some(label.to_string()),
+                metadata: vec![crate::Metadata::Offset],
+                ..AppConfig::default()
+            };
+
+            assert_eq!(
+                vec![
+                    vec![Value::Str("太郎".to_string()), Value::I64(0)],
+                    vec![Value::Str("花子".to_string()), Value::I64(*offset)]
+                ],
+                select_from_reader(
+                    config,
+                    &["名前 = {{ name }}"],
+                    input,
+                    "SELECT name, _offset FROM kaeshi"
+                )
+                .await
+            );
+        }
+    }
+
+    #[async_test]
+    async fn it_metadata_columns() {
+        let config = AppConfig {
+            metadata: vec![
+                crate::Metadata::Line,
+                crate::Metadata::Offset,
+                crate::Metadata::File,
+                crate::Metadata::Raw,
+            ],
+            file: Some(String::from("app.log")),
+            record: record::RecordMode::Indent,
+            ..AppConfig::default()
+        };
+        let rows = select_from_reader(
+            config,
+            &["ERROR {{ message }}"],
+            b"INFO ok\nERROR boom\n  at main\n",
+            "SELECT _line, _offset, _file, _raw FROM kaeshi",
+        )
+        .await;
+
+        assert_eq!(
+            vec![vec![
+                Value::I64(2),
+                Value::I64(8),
+                Value::Str("app.log".to_string()),
+                Value::Str("ERROR boom\n  at main".to_string())
+            ]],
+            rows
+        );
+    }
+
+    #[async_test]
+    async fn it_explain() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let template = TokenExpr::new_with_tag(&String::from("{{ a }}-{{ b }}!"));
+        tx.send(InputToken::Channel(String::from("x-y-z\n")))
+            .unwrap();
+        tx.send(InputToken::EOF).unwrap();
+
+        let mut lines = LineBuffer::new(&mut rx).explain(true);
+        let syn = parser::Syntax::default();
+        let record = TokenExpr::evaluate(&mut lines, &template.nodes(&syn).unwrap()).await;
+        let step = |n: &str, v: &str| explain::Step::Match(n.to_string(), v.to_string());
+
+        assert!(record.is_none());
+        assert_eq!(
+            vec![
+                step("{{ a }}", "x"),
+                step("\"-\"", "-"),
+                step("{{ b }}", "y-z"),
+                explain::Step::Fail(String::from("\"!\""), String::from("\n")),
+                explain::Step::Backtrack(String::from("{{ b }}")),
+                explain::Step::Fail(String::from("{{ b }}"), String::from("y-z\n")),
+                explain::Step::Backtrack(String::from("{{ a }}")),
+                step("{{ a }}", "x-y"),
+                step("\"-\"", "-"),
+                step("{{ b }}", "z"),
+                explain::Step::Fail(String::from("{{ b }}"), String::from("z\n")),
+                explain::Step::Backtrack(String::from("{{ a }}")),
+                step("{{ a }}", "x-y-z"),
+                explain::Step::Fail(String::from("\"-\""), String::from("\n")),
+                explain::Step::Backtrack(String::from("{{ a }}")),
+                explain::Step::Fail(String::from("{{ a }}"), String::from("x-y-z\n")),
+            ],
+            lines.take_trace()
+        );
+    }
+
+    #[test]
+    fn it_template_file() {
+        let path = std::env::temp_dir().join("kaeshi_it_template_file.kt");
+        std::fs::write(
+            &path,
+            "{# request line #}\n{{ method }} {# verb #}\\\n    {{ path }}\n",
+        )
+        .unwrap();
+
+        let template = TokenExpr::from_file(path.to_str().unwrap()).unwrap();
+        let syn = parser::Syntax::default();
+        let (_, tokens) = parser::parse(template.tag.as_bytes(), &syn).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(Some(String::from("kaeshi_it_template_file")), template.name);
+        assert_eq!(
+            vec![
+                parser::Node::Expr(
+                    parser::WS(false, false),
+                    parser::Expr::Var("method"),
+                    parser::Quantifier::Lazy
+                ),
+                parser::Node::Lit(" ", "", ""),
+                parser::Node::Expr(
+                    parser::WS(false, false),
+                    parser::Expr::Var("path"),
+                    parser::Quantifier::Lazy
+                ),
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn it_expand() {
+        let dir = std::env::temp_dir();
+        std::fs::write(
+            dir.join("kaeshi_it_expand.kt"),
+            "{% macro quoted(v) %}\"{{ v }}\"{% endmacro %}\n",
+        )
+        .unwrap();
+
+        let template = TokenExpr::new_with_tag(&String::from(
+            r#"{% include "kaeshi_it_expand.kt" %}{% macro ts() %}[{{ time }}]{% endmacro %}{% call ts() %} {% call quoted(agent) %}"#,
+        ))
+        .expand(&dir);
+        let _ = std::fs::remove_file(dir.join("kaeshi_it_expand.kt"));
+
+        assert_eq!(r#"[{{ time }}] "{{ agent }}""#, template.unwrap().tag);
+
+        let template = TokenExpr::new_with_tag(&String::from(
+            r#"{% macro m(v, w) %}{{ v|datetime("%v") }} {{ w }}{% endmacro %}{% call m(t, v) %}"#,
+        ))
+        .expand(&dir);
+        assert_eq!(r#"{{ t|datetime("%v") }} {{ v }}"#, template.unwrap().tag);
+
+        let template = TokenExpr::new_with_tag(&String::from(
+            r#"{% macro m(int, repeat) %}{{ int|int }} {% for repeat in repeat|sep(",") %}{{ v }}{% endfor %}{% endmacro %}{% call m(n, tags) %}"#,
+        ))
+        .expand(&dir);
+        assert_eq!(
+            r#"{{ n|int }} {% for tags in repeat|sep(",") %}{{ v }}{% endfor %}"#,
+            template.unwrap().tag
+        );
+        assert!(TokenExpr::new_with_tag(&String::from("{% call nope() %}"))
+            .expand(&dir)
+            .is_err());
+    }
+}
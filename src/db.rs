@@ -2,17 +2,94 @@ use anyhow::anyhow;
 
 use chrono::prelude::*;
 use gluesql::Payload;
+use serde::Deserialize;
 use sql_builder::esc;
 use std::collections::BTreeMap;
 
 use super::storage::MemoryStorage;
+use crate::{TimeFormat, TimeZone};
 use futures_await_test::async_test;
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColumnType {
+    #[default]
+    Text,
+    NullableText,
+    Int,
+    Float,
+    Timestamp,
+}
+
+impl ColumnType {
+    fn sql_type(&self) -> &'static str {
+        match self {
+            ColumnType::Text | ColumnType::NullableText => "TEXT",
+            ColumnType::Int => "INTEGER",
+            ColumnType::Float => "FLOAT",
+            ColumnType::Timestamp => "TIMESTAMP",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct IngestTime {
+    pub column: String,
+    pub zone: TimeZone,
+    pub format: TimeFormat,
+    pub as_text: bool,
+}
+
+impl IngestTime {
+    pub fn new(column: String) -> Self {
+        IngestTime {
+            column,
+            zone: TimeZone::Local,
+            format: TimeFormat::Rfc3339,
+            as_text: false,
+        }
+    }
+
+    fn column_type(&self) -> ColumnType {
+        match (self.as_text, &self.format) {
+            (true, _) => ColumnType::Text,
+            (false, TimeFormat::Rfc3339) => ColumnType::Timestamp,
+            (false, _) => ColumnType::Int,
+        }
+    }
+
+    fn now<Tz: chrono::TimeZone>(&self, now: DateTime<Tz>) -> String
+    where
+        Tz::Offset: std::fmt::Display,
+    {
+        match (&self.format, self.column_type()) {
+            (TimeFormat::Rfc3339, ColumnType::Timestamp) => {
+                Glue::sql_value(&now.naive_utc().format("%Y-%m-%d %H:%M:%S%.f").to_string())
+            }
+            (TimeFormat::Rfc3339, _) => Glue::sql_value(&now.to_rfc3339()),
+            (TimeFormat::EpochSeconds, ColumnType::Int) => now.timestamp().to_string(),
+            (TimeFormat::EpochSeconds, _) => Glue::sql_value(&now.timestamp().to_string()),
+            (TimeFormat::EpochMillis, ColumnType::Int) => now.timestamp_millis().to_string(),
+            (TimeFormat::EpochMillis, _) => Glue::sql_value(&now.timestamp_millis().to_string()),
+        }
+    }
+
+    fn value(&self) -> String {
+        match self.zone {
+            TimeZone::Local => self.now(Local::now()),
+            TimeZone::Utc => self.now(Utc::now()),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Table {
+    ingest_time: Option<IngestTime>,
+    columns: Vec<(String, ColumnType)>,
+}
+
 #[derive(Clone)]
 pub struct Glue {
-    table_name: Option<String>,
-    timestamp: Option<String>,
-    columns: Vec<String>,
+    tables: BTreeMap<String, Table>,
     storage: Option<MemoryStorage>,
 }
 
@@ -21,9 +98,7 @@ impl Glue {
         let storage = MemoryStorage::new();
 
         Glue {
-            table_name: None,
-            timestamp: None,
-            columns: Vec::default(),
+            tables: BTreeMap::default(),
             storage: Some(storage),
         }
     }
@@ -31,90 +106,125 @@ impl Glue {
     pub async fn create_table(
         &mut self,
         table_name: Option<String>,
-        columns: Vec<String>,
-        timestamp: Option<String>,
+        columns: Vec<(String, ColumnType)>,
+        ingest_time: Option<IngestTime>,
     ) -> anyhow::Result<Option<Payload>> {
-        self.table_name = table_name;
-        self.timestamp = timestamp;
+        let sql = self.create_table_sql(table_name, columns, ingest_time);
+        self.execute(sql.as_str()).await
+    }
 
-        self.columns = columns.iter().map(|c| c.to_string()).collect();
+    /// Registers the table for later inserts and returns the statement that creates it.
+    pub fn create_table_sql(
+        &mut self,
+        table_name: Option<String>,
+        columns: Vec<(String, ColumnType)>,
+        ingest_time: Option<IngestTime>,
+    ) -> String {
+        let table_name = Self::table_name(table_name.as_ref());
 
-        let s = self
-            .columns
+        let s = columns
             .iter()
-            .map(|s| format!(r#"{} TEXT"#, s.trim()))
+            .map(|(s, t)| format!(r#"{} {} NULL"#, s.trim(), t.sql_type()))
             .collect::<Vec<_>>()
             .join(",");
 
-        if let Some(timestamp) = &self.timestamp {
-            self.execute(
-                format!(
-                    "CREATE TABLE {} ({}, {} timestamp);",
-                    self.table_name(),
-                    s,
-                    timestamp
-                )
-                .as_str(),
+        let sql = if let Some(ingest_time) = &ingest_time {
+            format!(
+                "CREATE TABLE {} ({}, {} {});",
+                table_name,
+                s,
+                ingest_time.column,
+                ingest_time.column_type().sql_type()
             )
-            .await
         } else {
-            self.execute(format!("CREATE TABLE {} ({});", self.table_name(), s).as_str())
-                .await
-        }
+            format!("CREATE TABLE {} ({});", table_name, s)
+        };
+
+        self.tables.insert(
+            table_name,
+            Table {
+                ingest_time,
+                columns,
+            },
+        );
+
+        sql
     }
 
-    fn table_name(&self) -> String {
-        self.table_name
-            .as_ref()
-            .unwrap_or(&"kaeshi".to_string())
-            .to_string()
+    fn table_name(table_name: Option<&String>) -> String {
+        table_name.unwrap_or(&"kaeshi".to_string()).to_string()
     }
 
     fn sql_value(s: &String) -> String {
         format!("'{}'", esc(s))
     }
 
+    fn sql_literal(value: Option<&String>, column_type: &ColumnType) -> String {
+        match (value, column_type) {
+            (Some(v), ColumnType::Int) => v
+                .trim()
+                .parse::<i64>()
+                .map(|n| n.to_string())
+                .unwrap_or_else(|_| String::from("NULL")),
+            (Some(v), ColumnType::Float) => v
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| String::from("NULL")),
+            (Some(v), _) => Self::sql_value(v),
+            (None, ColumnType::Text) => Self::sql_value(&String::default()),
+            (None, _) => String::from("NULL"),
+        }
+    }
+
     pub async fn insert(
         &mut self,
+        table_name: &str,
         row: &BTreeMap<String, String>,
     ) -> anyhow::Result<Option<Payload>> {
-        let c = self
+        let sql = self.insert_sql(table_name, row)?;
+        self.execute(sql.as_str()).await
+    }
+
+    pub fn insert_sql(
+        &self,
+        table_name: &str,
+        row: &BTreeMap<String, String>,
+    ) -> anyhow::Result<String> {
+        let table = self
+            .tables
+            .get(table_name)
+            .ok_or_else(|| anyhow!("Error: no such table {}", table_name))?;
+
+        let c = table
             .columns
             .iter()
-            .map(|c| {
-                row.get(c)
-                    .map(|c| c.as_str().replace("'", "\'").to_string())
-                    .unwrap_or_default()
-            })
+            .map(|(c, t)| Self::sql_literal(row.get(c), t))
             .collect::<Vec<_>>();
 
-        let sql = if self.timestamp.is_none() {
+        let sql = if let Some(ingest_time) = &table.ingest_time {
             format!(
-                r#"INSERT INTO {} VALUES ({})"#,
-                self.table_name().as_str(),
-                c.iter().map(Self::sql_value).collect::<Vec<_>>().join(","),
+                r#"INSERT INTO {} VALUES ({}, {})"#,
+                table_name,
+                c.join(","),
+                ingest_time.value()
             )
         } else {
-            let local: DateTime<Local> = Local::now();
-
-            format!(
-                r#"INSERT INTO {} VALUES ({}, '{}')"#,
-                self.table_name().as_str(),
-                c.iter().map(Self::sql_value).collect::<Vec<_>>().join(","),
-                local.to_rfc3339().as_str()
-            )
+            format!(r#"INSERT INTO {} VALUES ({})"#, table_name, c.join(","),)
         };
 
-        self.execute(sql.as_str()).await
+        Ok(sql)
     }
 
     pub async fn execute(&mut self, sql: &str) -> anyhow::Result<Option<Payload>> {
-        let query = gluesql::parse(sql).unwrap();
+        let query = gluesql::parse(sql).map_err(|e| anyhow!("{}: {}", e, sql))?;
         let q = query.get(0);
 
         if let Some(q) = q {
+            let q = gluesql::translate(q).map_err(|e| anyhow!("{}: {}", e, sql))?;
             let storage = self.storage.take().unwrap();
-            let q = gluesql::translate(q).unwrap();
 
             if let Ok((s, payload)) = gluesql::execute(storage.clone(), &q).await {
                 self.storage = Some(s);
@@ -136,7 +246,11 @@ mod test {
     async fn it_select() {
         let mut glue = Glue::new();
         let _ = glue
-            .create_table(Some("kaeshi".to_string()), vec!["id".to_string()], None)
+            .create_table(
+                Some("kaeshi".to_string()),
+                vec![("id".to_string(), ColumnType::Text)],
+                None,
+            )
             .await;
         let query = glue.execute("SELECT * FROM kaeshi").await;
 
@@ -158,8 +272,8 @@ mod test {
         let _ = glue
             .create_table(
                 Some("kaeshi".to_string()),
-                vec!["id".to_string()],
-                Some(String::from("created_at")),
+                vec![("id".to_string(), ColumnType::Text)],
+                Some(IngestTime::new(String::from("created_at"))),
             )
             .await;
         let query = glue.execute("SELECT * FROM kaeshi").await;
@@ -175,4 +289,15 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn it_ingest_time_in_utc() {
+        let now = DateTime::parse_from_rfc3339("2021-09-10T06:04:05+09:00").unwrap();
+        let mut ingest_time = IngestTime::new(String::from("t"));
+
+        assert_eq!("'2021-09-09 21:04:05'", ingest_time.now(now));
+
+        ingest_time.as_text = true;
+        assert_eq!("'2021-09-10T06:04:05+09:00'", ingest_time.now(now));
+    }
 }
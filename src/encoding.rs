@@ -0,0 +1,174 @@
+use anyhow::anyhow;
+use encoding_rs::{Decoder, Encoding, UTF_16BE, UTF_16LE};
+use std::io::BufRead;
+
+pub const AUTO: &str = "auto";
+
+pub fn lookup(label: &str) -> anyhow::Result<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| anyhow!("unknown encoding {}", label))
+}
+
+pub fn input(label: &str) -> anyhow::Result<Option<&'static Encoding>> {
+    if label.eq_ignore_ascii_case(AUTO) {
+        Ok(None)
+    } else {
+        lookup(label).map(Some)
+    }
+}
+
+pub fn encode(text: &str, label: &str) -> anyhow::Result<Vec<u8>> {
+    let encoding = lookup(label)?;
+
+    if encoding == UTF_16LE {
+        Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect())
+    } else if encoding == UTF_16BE {
+        Ok(text.encode_utf16().flat_map(u16::to_be_bytes).collect())
+    } else {
+        Ok(encoding.encode(text).0.into_owned())
+    }
+}
+
+pub struct LineReader<R> {
+    reader: R,
+    encoding: Option<&'static Encoding>,
+    decoder: Option<Decoder>,
+    sniff: bool,
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub fn new(reader: R, label: Option<&str>) -> anyhow::Result<Self> {
+        Ok(LineReader {
+            reader,
+            encoding: match label {
+                Some(label) => input(label)?,
+                None => None,
+            },
+            decoder: None,
+            sniff: label.is_some(),
+        })
+    }
+
+    // Reads up to and including the next newline of the input encoding; in UTF-16 a `\n`
+    // byte is only a newline when it forms a whole code unit with the byte next to it.
+    fn read_raw(&mut self, buf: &mut Vec<u8>) -> std::io::Result<()> {
+        loop {
+            if self.reader.read_until(b'\n', buf)? == 0 {
+                return Ok(());
+            }
+
+            match self.encoding {
+                Some(encoding) if encoding == UTF_16LE && buf.len() % 2 == 1 => {
+                    let mut byte = [0; 1];
+                    if self.reader.read(&mut byte)? == 0 {
+                        return Ok(());
+                    }
+                    buf.push(byte[0]);
+
+                    if byte[0] == 0 {
+                        return Ok(());
+                    }
+                }
+                Some(encoding) if encoding == UTF_16LE => {}
+                Some(encoding) if encoding == UTF_16BE => {
+                    if buf.len() % 2 != 1 && buf[buf.len() - 2] == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Returns the next line decoded to UTF-8 with the number of input bytes it took.
+    pub fn next_line(&mut self) -> std::io::Result<Option<(String, usize)>> {
+        if self.sniff {
+            self.sniff = false;
+
+            if let Some((encoding, _)) = Encoding::for_bom(self.reader.fill_buf()?) {
+                self.encoding = Some(encoding);
+            }
+            self.decoder = self
+                .encoding
+                .map(|encoding| encoding.new_decoder_with_bom_removal());
+        }
+
+        let mut buf = Vec::with_capacity(1024);
+        self.read_raw(&mut buf)?;
+
+        let line = match self.decoder.as_mut() {
+            Some(decoder) => {
+                let mut line = String::with_capacity(
+                    decoder
+                        .max_utf8_buffer_length(buf.len())
+                        .unwrap_or(buf.len() * 3 + 16),
+                );
+                let _ = decoder.decode_to_string(&buf, &mut line, buf.is_empty());
+                line
+            }
+            None => String::from_utf8_lossy(&buf).to_string(),
+        };
+
+        if buf.is_empty() && line.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some((line, buf.len())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encode() {
+        assert_eq!(
+            vec![0x93, 0xfa, 0x96, 0x7b],
+            encode("日本", "Shift_JIS").unwrap()
+        );
+        assert_eq!(
+            vec![0xe5, 0x65, 0x2c, 0x67],
+            encode("日本", "utf-16le").unwrap()
+        );
+        assert_eq!(None, input("AUTO").unwrap());
+        assert_eq!(Some(encoding_rs::EUC_JP), input("euc-jp").unwrap());
+        assert!(input("klingon").is_err());
+    }
+
+    #[test]
+    fn it_line_reader() {
+        let lines = |input: Vec<u8>, label: Option<&str>| {
+            let mut reader = LineReader::new(std::io::Cursor::new(input), label).unwrap();
+            std::iter::from_fn(|| reader.next_line().unwrap()).collect::<Vec<_>>()
+        };
+        let line = |line: &str, len: usize| (line.to_string(), len);
+
+        assert_eq!(
+            vec![line("名前\n", 5), line("x\n", 2)],
+            lines(encode("名前\nx\n", "shift_jis").unwrap(), Some("shift_jis"))
+        );
+        assert_eq!(
+            vec![line("\u{a0a}\n", 6), line("x", 2)],
+            lines(
+                [
+                    &[0xff, 0xfe][..],
+                    &encode("\u{a0a}\nx", "utf-16le").unwrap()
+                ]
+                .concat(),
+                Some("auto")
+            )
+        );
+        assert_eq!(
+            vec![line("\u{a0a}\n", 4), line("x\n", 4)],
+            lines(
+                encode("\u{a0a}\nx\n", "utf-16be").unwrap(),
+                Some("utf-16be")
+            )
+        );
+        assert_eq!(
+            vec![line("a\u{fffd}\n", 3), line("b\n", 2)],
+            lines(b"a\xff\nb\n".to_vec(), None)
+        );
+    }
+}
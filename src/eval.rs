@@ -0,0 +1,269 @@
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use super::parser::Expr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl Value {
+    fn from_str(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+
+    pub fn truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Int(n) => *n != 0,
+            Value::Float(n) => *n != 0.0,
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+
+    fn number(&self) -> Option<Value> {
+        match self {
+            Value::Int(_) | Value::Float(_) => Some(self.clone()),
+            Value::Bool(b) => Some(Value::Int(*b as i64)),
+            Value::Str(s) => {
+                let s = s.trim();
+                s.parse::<i64>()
+                    .map(Value::Int)
+                    .or_else(|_| s.parse::<f64>().map(Value::Float))
+                    .ok()
+            }
+            Value::Null => None,
+        }
+    }
+
+    fn float(&self) -> Option<f64> {
+        match self.number()? {
+            Value::Int(n) => Some(n as f64),
+            Value::Float(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self.number()? {
+            Value::Int(n) => Some(n),
+            Value::Float(n) if n.fract() == 0.0 => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn string(&self) -> String {
+        match self {
+            Value::Null => String::default(),
+            Value::Bool(b) => b.to_string(),
+            Value::Int(n) => n.to_string(),
+            Value::Float(n) => n.to_string(),
+            Value::Str(s) => s.clone(),
+        }
+    }
+
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            _ => match (self.float(), other.float()) {
+                (Some(l), Some(r)) => l.partial_cmp(&r),
+                _ => match (self, other) {
+                    (Value::Str(l), Value::Str(r)) => Some(l.cmp(r)),
+                    _ => None,
+                },
+            },
+        }
+    }
+}
+
+pub fn eval(vars: &BTreeMap<String, String>, expr: &Expr) -> Value {
+    match expr {
+        Expr::BoolLit(b) => Value::Bool(*b == "true"),
+        Expr::NumLit(n) => Value::from_str(n).number().unwrap_or(Value::Null),
+        Expr::StrLit(s) | Expr::CharLit(s) => Value::from_str(s),
+        Expr::Var(name) => vars
+            .get(*name)
+            .map(|v| Value::from_str(v))
+            .unwrap_or(Value::Null),
+        Expr::Unary("!", e) => Value::Bool(!eval(vars, e).truthy()),
+        Expr::Unary("-", e) => match eval(vars, e).number() {
+            Some(Value::Int(n)) => n.checked_neg().map(Value::Int).unwrap_or(Value::Null),
+            Some(Value::Float(n)) => Value::Float(-n),
+            _ => Value::Null,
+        },
+        Expr::BinOp("&&", left, right) => {
+            Value::Bool(eval(vars, left).truthy() && eval(vars, right).truthy())
+        }
+        Expr::BinOp("||", left, right) => {
+            Value::Bool(eval(vars, left).truthy() || eval(vars, right).truthy())
+        }
+        Expr::BinOp(op, left, right) => bin_op(op, eval(vars, left), eval(vars, right)),
+        Expr::Filter(name, args) => {
+            let args = args.iter().map(|arg| eval(vars, arg)).collect::<Vec<_>>();
+            filter(name, &args)
+        }
+        _ => Value::Null,
+    }
+}
+
+pub fn truthy(vars: &BTreeMap<String, String>, expr: &Expr) -> bool {
+    eval(vars, expr).truthy()
+}
+
+pub fn int(vars: &BTreeMap<String, String>, expr: &Expr) -> Option<i64> {
+    eval(vars, expr).int()
+}
+
+fn bin_op(op: &str, left: Value, right: Value) -> Value {
+    let ordering = || left.compare(&right);
+
+    match op {
+        "==" | "!=" | "<" | ">" | "<=" | ">=" if left == Value::Null || right == Value::Null => {
+            Value::Bool(false)
+        }
+        "==" => Value::Bool(left == right || ordering() == Some(Ordering::Equal)),
+        "!=" => Value::Bool(!(left == right || ordering() == Some(Ordering::Equal))),
+        "<" => Value::Bool(ordering() == Some(Ordering::Less)),
+        ">" => Value::Bool(ordering() == Some(Ordering::Greater)),
+        "<=" => Value::Bool(matches!(ordering(), Some(Ordering::Less | Ordering::Equal))),
+        ">=" => Value::Bool(matches!(
+            ordering(),
+            Some(Ordering::Greater | Ordering::Equal)
+        )),
+        "+" | "-" | "*" | "/" | "%" => arithmetic(op, &left, &right),
+        "&" | "|" | "^" | "<<" | ">>" => match (left.int(), right.int()) {
+            (Some(l), Some(r)) => match op {
+                "&" => Value::Int(l & r),
+                "|" => Value::Int(l | r),
+                "^" => Value::Int(l ^ r),
+                "<<" => l
+                    .checked_shl(r as u32)
+                    .map(Value::Int)
+                    .unwrap_or(Value::Null),
+                _ => l
+                    .checked_shr(r as u32)
+                    .map(Value::Int)
+                    .unwrap_or(Value::Null),
+            },
+            _ => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+fn arithmetic(op: &str, left: &Value, right: &Value) -> Value {
+    match (left.number(), right.number()) {
+        (Some(Value::Int(l)), Some(Value::Int(r))) => match op {
+            "+" => l.checked_add(r),
+            "-" => l.checked_sub(r),
+            "*" => l.checked_mul(r),
+            "/" => l.checked_div(r),
+            _ => l.checked_rem(r),
+        }
+        .map(Value::Int)
+        .unwrap_or(Value::Null),
+        (Some(l), Some(r)) => {
+            let (l, r) = (l.float().unwrap_or_default(), r.float().unwrap_or_default());
+
+            match op {
+                "+" => Value::Float(l + r),
+                "-" => Value::Float(l - r),
+                "*" => Value::Float(l * r),
+                "/" if r != 0.0 => Value::Float(l / r),
+                "%" if r != 0.0 => Value::Float(l % r),
+                _ => Value::Null,
+            }
+        }
+        _ if op == "+" && *left != Value::Null && *right != Value::Null => {
+            Value::Str(format!("{}{}", left.string(), right.string()))
+        }
+        _ => Value::Null,
+    }
+}
+
+fn filter(name: &str, args: &[Value]) -> Value {
+    let value = args.first().cloned().unwrap_or(Value::Null);
+    let arg = |n: usize| args.get(n).map(Value::string).unwrap_or_default();
+
+    match (name, &value) {
+        ("default", Value::Null) => args.get(1).cloned().unwrap_or(Value::Null),
+        ("default", _) => value,
+        (_, Value::Null) => Value::Null,
+        ("lower", _) => Value::Str(value.string().to_lowercase()),
+        ("upper", _) => Value::Str(value.string().to_uppercase()),
+        ("trim", _) => Value::Str(value.string().trim().to_string()),
+        ("length", _) => Value::Int(value.string().chars().count() as i64),
+        ("contains", _) => Value::Bool(value.string().contains(&arg(1))),
+        ("starts_with", _) => Value::Bool(value.string().starts_with(&arg(1))),
+        ("ends_with", _) => Value::Bool(value.string().ends_with(&arg(1))),
+        ("replace", _) => Value::Str(value.string().replace(&arg(1), &arg(2))),
+        ("matches", _) => Regex::new(&arg(1))
+            .map(|re| Value::Bool(re.is_match(&value.string())))
+            .unwrap_or(Value::Null),
+        ("int", _) => value
+            .number()
+            .and_then(|n| n.int())
+            .map(Value::Int)
+            .unwrap_or(Value::Null),
+        ("float", _) => value.float().map(Value::Float).unwrap_or(Value::Null),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{parse_template, Node, Syntax};
+
+    fn eval_str(vars: &[(&str, &str)], source: &str) -> Value {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>();
+        let syn = Syntax::default();
+        let template = format!("{{{{ {} }}}}", source);
+
+        match parse_template(template.as_bytes(), &syn) {
+            Ok((_, nodes)) => match nodes.first() {
+                Some(Node::Expr(_, expr, _)) => eval(&vars, expr),
+                n => panic!("{:?}", n),
+            },
+            n => panic!("{:?}", n),
+        }
+    }
+
+    #[test]
+    fn it_eval() {
+        assert_eq!(Value::Int(5), eval_str(&[], "10 - 2 - 3"));
+        assert_eq!(Value::Int(7), eval_str(&[], "1 + 2 * 3"));
+        assert_eq!(
+            Value::Bool(true),
+            eval_str(&[], "1 == 2 && 3 == 4 || 5 == 5")
+        );
+        assert_eq!(
+            Value::Bool(true),
+            eval_str(&[("a", "10")], "a > 9 && !(a > 10)")
+        );
+        assert_eq!(Value::Bool(false), eval_str(&[("a", "abc")], "a > 9"));
+        assert_eq!(
+            Value::Bool(true),
+            eval_str(&[("a", "abc")], r#"a == "abc""#)
+        );
+        assert_eq!(Value::Int(6), eval_str(&[], "12 & 6 | 2"));
+        assert_eq!(Value::Null, eval_str(&[], "1 / 0"));
+        assert_eq!(Value::Bool(false), eval_str(&[], "missing == 1"));
+        assert_eq!(Value::Bool(false), eval_str(&[], "missing == missing"));
+        assert_eq!(Value::Bool(false), eval_str(&[], "missing != 1"));
+        assert_eq!(
+            Value::Bool(true),
+            eval_str(&[("m", " GET ")], r#"m|trim|lower|starts_with("ge")"#)
+        );
+    }
+}
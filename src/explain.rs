@@ -0,0 +1,151 @@
+use unicode_width::UnicodeWidthStr;
+
+use super::parser::{Expr, Node, Target};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Match(String, String),
+    Fail(String, String),
+    Backtrack(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub template: String,
+    pub steps: Vec<Step>,
+    pub matched: bool,
+}
+
+pub fn expr(e: &Expr) -> String {
+    match e {
+        Expr::BoolLit(s) | Expr::NumLit(s) | Expr::Var(s) => s.to_string(),
+        Expr::StrLit(s) => format!("\"{}\"", s),
+        Expr::CharLit(s) => format!("'{}'", s),
+        Expr::Filter(name, args) => {
+            let params = args.iter().skip(1).map(expr).collect::<Vec<_>>();
+            let inner = args.first().map(expr).unwrap_or_default();
+
+            if params.is_empty() {
+                format!("{}|{}", inner, name)
+            } else {
+                format!("{}|{}({})", inner, name, params.join(", "))
+            }
+        }
+        Expr::Unary(op, e) => format!("{}{}", op, expr(e)),
+        Expr::Range(op, s, e) => format!(
+            "{}{}{}",
+            s.as_ref().map(|s| expr(s)).unwrap_or_default(),
+            op,
+            e.as_ref().map(|e| expr(e)).unwrap_or_default()
+        ),
+        Expr::BinOp(op, l, r) => format!("{} {} {}", expr(l), op, expr(r)),
+    }
+}
+
+pub fn node(n: &Node) -> String {
+    match n {
+        Node::Lit(a, b, c) => format!("{:?}", format!("{}{}{}", a, b, c)),
+        Node::Expr(_, e, _) => format!("{{{{ {} }}}}", expr(e)),
+        Node::Cond(..) => String::from("{% if %}"),
+        Node::Loop(_, Target::Name(name), iter, _, _) => {
+            format!("{{% for {} in {} %}}", name, expr(iter))
+        }
+        Node::Loop(_, Target::Tuple(names), iter, _, _) => {
+            format!("{{% for {} in {} %}}", names.join(", "), expr(iter))
+        }
+        Node::Optional(..) => String::from("{% optional %}"),
+        Node::Ws => String::from("-"),
+    }
+}
+
+fn highlight(text: &str, rest: &str) -> String {
+    let offset = match text.strip_suffix(rest) {
+        Some(consumed) => consumed.len(),
+        None => return format!("      {:?}\n", rest),
+    };
+    let start = text[..offset].rfind('\n').map_or(0, |n| n + 1);
+    let end = text[offset..].find('\n').map_or(text.len(), |n| offset + n);
+    let indent = text[start..offset].width();
+    let marks = text[offset..end].width().max(1);
+
+    format!(
+        "      {}\n      {}{}\n",
+        &text[start..end],
+        " ".repeat(indent),
+        "^".repeat(marks)
+    )
+}
+
+pub fn report(n: usize, text: &str, attempts: &[Attempt]) -> String {
+    let mut out = format!(
+        "record {}: {:?}\n",
+        n,
+        text.strip_suffix('\n').unwrap_or(text)
+    );
+
+    for attempt in attempts.iter() {
+        out.push_str(&format!(
+            "  template {}: {}\n",
+            attempt.template,
+            if attempt.matched {
+                "matched"
+            } else {
+                "no match"
+            }
+        ));
+
+        for step in attempt.steps.iter() {
+            match step {
+                Step::Match(node, value) => {
+                    out.push_str(&format!("    {:<24} {:?}\n", node, value));
+                }
+                Step::Backtrack(node) => {
+                    out.push_str(&format!("    {:<24} backtracking\n", node));
+                }
+                Step::Fail(node, rest) => {
+                    out.push_str(&format!("    {:<24} failed at\n", node));
+                    out.push_str(&highlight(text, rest));
+                }
+            }
+        }
+    }
+
+    if attempts.iter().all(|attempt| !attempt.matched) {
+        out.push_str("  no template matched\n");
+    }
+
+    out
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_report() {
+        let text = "GET /index.html 200\n";
+        let attempts = [Attempt {
+            template: String::from("0"),
+            steps: vec![
+                Step::Match(String::from("{{ method }}"), String::from("GET")),
+                Step::Match(String::from("\" \""), String::from(" ")),
+                Step::Fail(String::from("\"/api/\""), String::from("/index.html 200\n")),
+            ],
+            matched: false,
+        }];
+
+        assert_eq!(
+            concat!(
+                "record 1: \"GET /index.html 200\"\n",
+                "  template 0: no match\n",
+                "    {{ method }}             \"GET\"\n",
+                "    \" \"                      \" \"\n",
+                "    \"/api/\"                  failed at\n",
+                "      GET /index.html 200\n",
+                "          ^^^^^^^^^^^^^^^\n",
+                "  no template matched\n",
+            ),
+            report(1, text, &attempts)
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use chrono::prelude::*;
+use std::collections::HashSet;
+use unicode_width::UnicodeWidthChar;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+pub fn datetime(value: &str, format: &str) -> Option<String> {
+    let value = value.trim();
+
+    let utc = if let Ok(t) = DateTime::parse_from_str(value, format) {
+        t.with_timezone(&Utc).naive_utc()
+    } else if let Ok(t) = NaiveDateTime::parse_from_str(value, format) {
+        t
+    } else if let Ok(d) = NaiveDate::parse_from_str(value, format) {
+        d.and_hms(0, 0, 0)
+    } else {
+        return None;
+    };
+
+    Some(utc.format(TIMESTAMP_FORMAT).to_string())
+}
+
+pub fn width(value: &str, width: usize, display: bool) -> usize {
+    let mut columns = 0;
+
+    for (idx, c) in value.char_indices() {
+        columns += if display { c.width().unwrap_or(0) } else { 1 };
+
+        if c == '\n' || columns > width {
+            return idx;
+        }
+    }
+
+    value.len()
+}
+
+pub fn identifier(name: &str) -> String {
+    let name = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+pub fn unique<I: IntoIterator<Item = String>>(names: I) -> Vec<String> {
+    let mut seen = HashSet::new();
+
+    names
+        .into_iter()
+        .map(|name| {
+            let name = (1..)
+                .map(|n| match n {
+                    1 => name.clone(),
+                    n => format!("{}_{}", name, n),
+                })
+                .find(|name| !seen.contains(name))
+                .unwrap_or_default();
+            seen.insert(name.clone());
+            name
+        })
+        .collect()
+}
+
+fn identifiers(pairs: Vec<(String, String)>) -> Vec<(String, String)> {
+    let (keys, values): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+
+    unique(keys.iter().map(|key| identifier(key)))
+        .into_iter()
+        .zip(values)
+        .collect()
+}
+
+pub fn kv(value: &str) -> Option<Vec<(String, String)>> {
+    let mut pairs = Vec::default();
+    let mut chars = value.trim_end_matches('\n').chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let key = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && !c.is_whitespace()))
+            .collect::<String>();
+
+        if key.is_empty() {
+            return if chars.peek().is_none() {
+                Some(identifiers(pairs))
+            } else {
+                None
+            };
+        }
+
+        let mut value = String::default();
+
+        if chars.next_if_eq(&'=').is_some() {
+            if chars.next_if_eq(&'"').is_some() {
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => value.push(chars.next()?),
+                        c => value.push(c),
+                    }
+                }
+
+                if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                    return None;
+                }
+            } else {
+                value.extend(std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())));
+            }
+        }
+
+        if pairs.iter().all(|(k, _)| *k != key) {
+            pairs.push((key, value));
+        }
+    }
+}
+
+pub fn csv(value: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '"' && chars.next()? != '"' {
+            return None;
+        }
+        unescaped.push(c);
+    }
+
+    Some(unescaped)
+}
+
+pub fn json(value: &str) -> Option<serde_json::Value> {
+    serde_json::from_str(value.trim()).ok()
+}
+
+pub fn json_path<'v>(value: &'v serde_json::Value, path: &str) -> Option<&'v serde_json::Value> {
+    let mut pointer = String::default();
+    let mut rest = path.strip_prefix('$')?;
+
+    while !rest.is_empty() {
+        let (segment, next) = if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            (&r[..end], &r[end..])
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']')?;
+            (
+                r[..end].trim_matches(|c| c == '\'' || c == '"'),
+                &r[end + 1..],
+            )
+        } else {
+            return None;
+        };
+
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+        rest = next;
+    }
+
+    value.pointer(&pointer)
+}
+
+pub fn json_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+pub fn flatten(value: &serde_json::Value, prefix: &str) -> Vec<(String, String)> {
+    fn leaves(value: &serde_json::Value, prefix: String, pairs: &mut Vec<(String, String)>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, v) in map.iter() {
+                    leaves(v, format!("{}_{}", prefix, key), pairs);
+                }
+            }
+            v => pairs.push((prefix, json_text(v))),
+        }
+    }
+
+    let mut pairs = Vec::default();
+    leaves(value, prefix.to_string(), &mut pairs);
+    identifiers(pairs)
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_datetime_to_utc() {
+        assert_eq!(
+            Some(String::from("2021-09-09 21:04:05")),
+            datetime("10/Sep/2021:06:04:05 +0900", "%d/%b/%Y:%H:%M:%S %z")
+        );
+        assert_eq!(
+            Some(String::from("2021-09-09 06:04:05.250")),
+            datetime("2021-09-09 06:04:05.250", "%Y-%m-%d %H:%M:%S%.f")
+        );
+        assert_eq!(
+            Some(String::from("2021-09-09 00:00:00")),
+            datetime("2021/09/09", "%Y/%m/%d")
+        );
+        assert_eq!(None, datetime("yesterday", "%Y/%m/%d"));
+    }
+
+    #[test]
+    fn it_width() {
+        assert_eq!(3, width("abcdef", 3, false));
+        assert_eq!(2, width("ab\ncd", 3, false));
+        assert_eq!(6, width("日本語", 2, false));
+        assert_eq!(3, width("日本語", 2, true));
+    }
+
+    #[test]
+    fn it_kv() {
+        let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+
+        assert_eq!(
+            Some(vec![
+                pair("level", "info"),
+                pair("msg", "slow \"query\""),
+                pair("http_status", "200"),
+                pair("cached", "")
+            ]),
+            kv(r#"level=info msg="slow \"query\"" http.status=200 cached"#)
+        );
+        assert_eq!(Some(vec![]), kv("  \n"));
+        assert_eq!(None, kv(r#"msg="unterminated"#));
+        assert_eq!(None, kv(r#"msg="a"b"#));
+        assert_eq!(
+            Some(vec![
+                pair("_1st", "1"),
+                pair("__", "2"),
+                pair("____", "3"),
+                pair("a", "4")
+            ]),
+            kv("1st=1 名前=2 ключ=3 a=4 a=5")
+        );
+    }
+
+    #[test]
+    fn it_csv() {
+        assert_eq!(Some(String::from(r#"say "hi""#)), csv(r#"say ""hi"""#));
+        assert_eq!(None, csv(r#"a","b"#));
+        assert_eq!(None, csv(r#"a""#));
+    }
+
+    #[test]
+    fn it_identifier() {
+        assert_eq!("http_status", identifier(" http.status "));
+        assert_eq!("_1st", identifier("1st"));
+        assert_eq!("__", identifier("名前"));
+        assert_eq!(
+            vec!["a_b", "a_b_2", "a_b_3", "a_b_2_2"],
+            unique(vec![
+                String::from("a_b"),
+                String::from("a_b"),
+                String::from("a_b"),
+                String::from("a_b_2")
+            ])
+        );
+    }
+
+    #[test]
+    fn it_json() {
+        let value = json(r#" {"user": {"id": 42, "name": "alice"}, "tags": ["a", "b"]} "#).unwrap();
+        let text = |path: &str| json_path(&value, path).map(json_text);
+
+        assert_eq!(Some(String::from("42")), text("$.user.id"));
+        assert_eq!(Some(String::from("alice")), text("$['user'].name"));
+        assert_eq!(Some(String::from("b")), text("$.tags[1]"));
+        assert_eq!(None, text("$.user.email"));
+        assert_eq!(None, json("{\"user\": "));
+        assert_eq!(
+            vec![
+                (String::from("p_tags"), String::from(r#"["a","b"]"#)),
+                (String::from("p_user_id"), String::from("42")),
+                (String::from("p_user_name"), String::from("alice"))
+            ],
+            flatten(&value, "p")
+        );
+
+        let value = json(r#"{"a-b": 1, "a_b": 2, "a": {"b": 3}, "1st": {"名": 4}}"#).unwrap();
+        assert_eq!(
+            vec![
+                (String::from("p_1st__"), String::from("4")),
+                (String::from("p_a_b"), String::from("3")),
+                (String::from("p_a_b_2"), String::from("1")),
+                (String::from("p_a_b_3"), String::from("2"))
+            ],
+            flatten(&value, "p")
+        );
+    }
+}
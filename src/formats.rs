@@ -0,0 +1,249 @@
+use super::app::TokenExpr;
+use super::record::RecordMode;
+use crate::Format;
+
+const CLF: &str = r#"{{ host }} {{ ident }} {{ user }} [{{ time|datetime("%d/%b/%Y:%H:%M:%S %z") }}] "{{ method }} {{ path }} {{ protocol }}" {{ status|int }} {{ size|int }}"#;
+
+const SYSLOG: &str = r#"{% optional %}<{{ priority|int }}>{% endoptional %}{{ timestamp|width(15) }} {{ hostname }} {{ app }}{% optional %}[{{ pid|int }}]{% endoptional %}: {{ message }}"#;
+
+const SYSLOG_5424: &str = r#"<{{ priority|int }}>{{ version|int }} {% optional %}- {% endoptional %}{% optional %}{{ timestamp|datetime("%+") }} {% endoptional %}{{ hostname }} {{ app }} {{ procid }} {{ msgid }} {% optional %}- {% endoptional %}{% optional %}[{{ structured_data }}] {% endoptional %}{{ message }}"#;
+
+const LOGFMT: &str = r#"{{ fields|kv }}"#;
+
+const JOURNALD: &str = r#"{% for fields in repeat|sep("\n") %}{{ key }}={{ value }}{% endfor %}"#;
+
+const HAPROXY: &str = r#"{{ client_ip }}:{{ client_port|int }} [{{ accept_date|datetime("%d/%b/%Y:%H:%M:%S%.3f") }}] {{ frontend }} {{ backend }}/{{ server }} {{ tr|int }}/{{ tw|int }}/{{ tc|int }}/{{ tr_server|int }}/{{ ta|int }} {{ status|int }} {{ bytes|int }} {{ request_cookie }} {{ response_cookie }} {{ termination_state }} {{ actconn|int }}/{{ feconn|int }}/{{ beconn|int }}/{{ srv_conn|int }}/{{ retries }} {{ srv_queue|int }}/{{ backend_queue|int }} {% optional %}{{ headers|skip }} {% endoptional %}"{{ request }}""#;
+
+const POSTGRES_CSV: &str = r#"{{ log_time|width(23)|datetime("%Y-%m-%d %H:%M:%S%.3f") }} {{ log_time_zone }},{% optional %}"{{ user_name|csv }}"{% endoptional %},{% optional %}"{{ database_name|csv }}"{% endoptional %},{{ process_id|int }},{% optional %}"{{ connection_from|csv }}"{% endoptional %},{{ session_id }},{{ session_line_num|int }},{% optional %}"{{ command_tag|csv }}"{% endoptional %},{{ session_start_time }},{{ virtual_transaction_id }},{{ transaction_id|int }},{{ error_severity }},{{ sql_state_code }},{% optional %}"{{ message|csv }}"{% endoptional %},{% optional %}"{{ detail|csv }}"{% endoptional %},{% optional %}"{{ hint|csv }}"{% endoptional %},"#;
+
+const AWS_ELB: &str = r#"{{ timestamp|datetime("%+") }} {{ elb }} {{ client_ip }}:{{ client_port|int }} {{ backend }} {{ request_processing_time|float }} {{ backend_processing_time|float }} {{ response_processing_time|float }} {{ elb_status_code|int }} {{ backend_status_code|int }} {{ received_bytes|int }} {{ sent_bytes|int }} "{{ request }}" "{{ user_agent }}" {{ ssl_cipher }} {{ ssl_protocol }}"#;
+
+impl Format {
+    pub fn template(&self) -> TokenExpr {
+        let tag = match self {
+            Format::Clf => String::from(CLF),
+            Format::Combined | Format::Nginx => {
+                format!(r#"{} "{{{{ referer }}}}" "{{{{ user_agent }}}}""#, CLF)
+            }
+            Format::Syslog => String::from(SYSLOG),
+            Format::Syslog5424 => String::from(SYSLOG_5424),
+            Format::Logfmt => String::from(LOGFMT),
+            Format::Journald => String::from(JOURNALD),
+            Format::Haproxy => String::from(HAPROXY),
+            Format::PostgresCsv => String::from(POSTGRES_CSV),
+            Format::AwsElb => String::from(AWS_ELB),
+        };
+
+        let mut template = TokenExpr::new_with_tag(&tag);
+        template.name = Some(self.to_string().to_lowercase());
+        template
+    }
+
+    pub fn record(&self) -> RecordMode {
+        match self {
+            Format::Journald => RecordMode::Blank,
+            _ => RecordMode::Line,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::select;
+    use futures_await_test::async_test;
+    use gluesql::data::Value;
+
+    async fn parse(format: Format, lines: &[&str], sql: &str) -> Vec<Vec<Value>> {
+        select(&[format.template().tag.as_str()], lines, sql).await
+    }
+
+    fn s(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+
+    #[async_test]
+    async fn it_access_log() {
+        let line = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#;
+
+        let rows = parse(
+            Format::Clf,
+            &[line],
+            "SELECT host, user, path, status, size FROM kaeshi",
+        )
+        .await;
+        assert_eq!(
+            vec![vec![
+                s("127.0.0.1"),
+                s("frank"),
+                s("/apache_pb.gif"),
+                Value::I64(200),
+                Value::I64(2326)
+            ]],
+            rows
+        );
+
+        let combined = format!(
+            r#"{} "http://example.com/" "Mozilla/4.08 [en] (Win98)""#,
+            line
+        );
+        let rows = parse(
+            Format::Combined,
+            &[&combined],
+            "SELECT referer, user_agent FROM kaeshi WHERE time = '2000-10-10 20:55:36'",
+        )
+        .await;
+        assert_eq!(
+            vec![vec![
+                s("http://example.com/"),
+                s("Mozilla/4.08 [en] (Win98)")
+            ]],
+            rows
+        );
+    }
+
+    #[async_test]
+    async fn it_syslog() {
+        let rows = parse(
+            Format::Syslog,
+            &[
+                "<34>Oct 11 22:14:15 mymachine su[230]: 'su root' failed",
+                "Oct  1 01:02:03 host kernel: [0.000000] Linux version",
+            ],
+            "SELECT priority, timestamp, app, pid, message FROM kaeshi",
+        )
+        .await;
+        assert_eq!(
+            vec![
+                Value::I64(34),
+                s("Oct 11 22:14:15"),
+                s("su"),
+                Value::I64(230),
+                s("'su root' failed")
+            ],
+            rows[0]
+        );
+        assert_eq!(s("kernel"), rows[1][2]);
+        assert_eq!(s("[0.000000] Linux version"), rows[1][4]);
+
+        let rows = parse(
+            Format::Syslog5424,
+            &[
+                r#"<165>1 2003-10-11T22:14:15.003Z host evntslog - ID47 [exampleSDID@32473 iut="3"] An application event"#,
+                "<34>1 2003-10-11T22:14:15.003Z mymachine su - ID47 - 'su root' failed",
+                "<34>1 - host app - - - msg",
+            ],
+            "SELECT app, msgid, structured_data, message, timestamp FROM kaeshi",
+        )
+        .await;
+        assert_eq!(
+            vec![
+                s("evntslog"),
+                s("ID47"),
+                s(r#"exampleSDID@32473 iut="3""#),
+                s("An application event"),
+                Value::Timestamp(
+                    chrono::NaiveDate::from_ymd(2003, 10, 11).and_hms_milli(22, 14, 15, 3)
+                )
+            ],
+            rows[0]
+        );
+        assert_eq!(s("'su root' failed"), rows[1][3]);
+        assert_eq!(vec![s("app"), s("-")], rows[2][..2]);
+        assert_eq!(s("msg"), rows[2][3]);
+        assert!(matches!(rows[2][4], Value::Null));
+    }
+
+    #[async_test]
+    async fn it_key_values() {
+        let rows = parse(
+            Format::Logfmt,
+            &[r#"level=info msg="server started" port=8080"#],
+            "SELECT level, msg, port FROM kaeshi",
+        )
+        .await;
+        assert_eq!(vec![vec![s("info"), s("server started"), s("8080")]], rows);
+
+        let rows = parse(
+            Format::Journald,
+            &["__CURSOR=s=1\nMESSAGE=hello world\n"],
+            "SELECT value FROM kaeshi_items WHERE key = 'MESSAGE'",
+        )
+        .await;
+        assert_eq!(vec![vec![s("hello world")]], rows);
+    }
+
+    #[async_test]
+    async fn it_haproxy() {
+        let rows = parse(
+            Format::Haproxy,
+            &[
+                r#"10.0.1.2:33317 [06/Feb/2009:12:14:14.655] http-in static/srv1 10/0/30/69/109 200 2750 - - ---- 1/1/1/1/0 0/0 {1wt.eu} {} "GET /index.html HTTP/1.1""#,
+                r#"10.0.1.2:33318 [06/Feb/2009:12:14:15.001] http-in static/srv2 -1/0/30/69/109 503 212 - - SC-- 1/1/1/1/0 0/0 "GET /a b HTTP/1.1""#,
+            ],
+            "SELECT server, tr, status, request FROM kaeshi",
+        )
+        .await;
+        assert_eq!(
+            vec![
+                vec![
+                    s("srv1"),
+                    Value::I64(10),
+                    Value::I64(200),
+                    s("GET /index.html HTTP/1.1")
+                ],
+                vec![
+                    s("srv2"),
+                    Value::I64(-1),
+                    Value::I64(503),
+                    s("GET /a b HTTP/1.1")
+                ]
+            ],
+            rows
+        );
+    }
+
+    #[async_test]
+    async fn it_postgres_csv() {
+        let rows = parse(
+            Format::PostgresCsv,
+            &[
+                r#"2021-09-09 06:04:05.250 UTC,"postgres","app",1234,"[local]",6139a3b5.4d2,1,"SELECT",2021-09-09 06:04:01 UTC,3/0,0,ERROR,42P01,"relation ""t"" does not exist, sorry",,,,,,"select * from t;",15,,"psql","client backend""#,
+                r#"2021-09-09 06:04:06.000 UTC,,,1200,,6139a3b5.4b0,1,,2021-09-09 06:00:00 UTC,,0,LOG,00000,"checkpoint starting: time",,,,,,,,,"","checkpointer""#,
+            ],
+            "SELECT user_name, process_id, error_severity, message FROM kaeshi ORDER BY log_time",
+        )
+        .await;
+        assert_eq!(
+            vec![
+                s("postgres"),
+                Value::I64(1234),
+                s("ERROR"),
+                s(r#"relation "t" does not exist, sorry"#)
+            ],
+            rows[0]
+        );
+        assert_eq!(s("checkpoint starting: time"), rows[1][3]);
+    }
+
+    #[async_test]
+    async fn it_aws_elb() {
+        let rows = parse(
+            Format::AwsElb,
+            &[
+                r#"2015-05-13T23:39:43.945958Z my-loadbalancer 192.168.131.39:2817 10.0.0.1:80 0.000073 0.001048 0.000057 200 200 0 29 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.38.0" - -"#,
+            ],
+            "SELECT client_ip, backend_processing_time, elb_status_code, user_agent FROM kaeshi",
+        )
+        .await;
+        assert_eq!(
+            vec![vec![
+                s("192.168.131.39"),
+                Value::F64(0.001048),
+                Value::I64(200),
+                s("curl/7.38.0")
+            ]],
+            rows
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use regex::Regex;
+use std::collections::HashSet;
+
+use super::filter;
+
+const DATETIME_FORMATS: [&str; 6] = [
+    "%+",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%d/%b/%Y:%H:%M:%S %z",
+    "%d/%b/%Y:%H:%M:%S",
+    "%Y-%m-%d",
+];
+
+const NULL: &str = "-";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Word(&'a str),
+    Sep(&'a str),
+}
+
+#[derive(Debug)]
+enum Part {
+    Lit(String),
+    Capture(Vec<String>),
+}
+
+fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+
+    for (idx, c) in line.char_indices() {
+        let word = c.is_alphanumeric() || "_.-/:@+".contains(c);
+
+        if !word {
+            if start < idx {
+                tokens.push(Token::Word(&line[start..idx]));
+            }
+            tokens.push(Token::Sep(&line[idx..idx + c.len_utf8()]));
+            start = idx + c.len_utf8();
+        }
+    }
+
+    if start < line.len() {
+        tokens.push(Token::Word(&line[start..]));
+    }
+
+    tokens
+}
+
+fn shape<'a>(lines: &[Vec<Token<'a>>]) -> (usize, bool) {
+    let len = lines.iter().map(Vec::len).min().unwrap_or(0);
+    let same = |idx: usize| {
+        lines
+            .iter()
+            .all(|tokens| match (&tokens[idx], &lines[0][idx]) {
+                (Token::Word(_), Token::Word(_)) => true,
+                (a, b) => a == b,
+            })
+    };
+
+    match (0..len).find(|idx| !same(*idx)) {
+        None if lines.iter().all(|tokens| tokens.len() == len) => (len, false),
+        end => {
+            let end = end.unwrap_or(len);
+            let cut = (0..end)
+                .rev()
+                .find(|idx| matches!(lines[0][*idx], Token::Word(_)))
+                .unwrap_or(0);
+            (cut, true)
+        }
+    }
+}
+
+fn datetime_format(values: &[String]) -> Option<&'static str> {
+    DATETIME_FORMATS.iter().cloned().find(|format| {
+        values
+            .iter()
+            .all(|value| value == NULL || filter::datetime(value, format).is_some())
+    })
+}
+
+fn text<'a>(token: &Token<'a>) -> &'a str {
+    match token {
+        Token::Word(s) | Token::Sep(s) => s,
+    }
+}
+
+fn joined_datetime(samples: &[Vec<Token>], idx: usize, cut: usize) -> Option<Vec<String>> {
+    if idx + 2 >= cut || samples[0][idx + 1] != Token::Sep(" ") {
+        return None;
+    }
+
+    let joined = samples
+        .iter()
+        .map(|tokens| tokens[idx..idx + 3].iter().map(text).collect::<String>())
+        .collect::<Vec<_>>();
+
+    datetime_format(&joined).map(|_| joined)
+}
+
+fn parts(samples: &[Vec<Token>]) -> Vec<Part> {
+    let (cut, trailing) = shape(samples);
+    let mut parts: Vec<Part> = Vec::new();
+    let mut idx = 0;
+
+    while idx < cut {
+        if let Some(values) = joined_datetime(samples, idx, cut) {
+            parts.push(Part::Capture(values));
+            idx += 3;
+            continue;
+        }
+
+        let values = samples
+            .iter()
+            .map(|tokens| text(&tokens[idx]).to_string())
+            .collect::<Vec<_>>();
+        let constant = values.iter().all(|v| *v == values[0]);
+
+        match &samples[0][idx] {
+            Token::Word(_) if !constant || samples.len() == 1 => parts.push(Part::Capture(values)),
+            token => match parts.last_mut() {
+                Some(Part::Lit(lit)) => lit.push_str(text(token)),
+                _ => parts.push(Part::Lit(text(token).to_string())),
+            },
+        }
+
+        idx += 1;
+    }
+
+    if trailing {
+        let values = samples
+            .iter()
+            .map(|tokens| tokens[cut..].iter().map(text).collect::<String>())
+            .collect();
+        parts.push(Part::Capture(values));
+    }
+
+    parts
+}
+
+fn name(values: &[String], prev: Option<&str>) -> String {
+    let key = prev
+        .and_then(|lit| lit.strip_suffix('=').or_else(|| lit.strip_suffix(": ")))
+        .and_then(|lit| {
+            lit.rsplit(|c: char| !c.is_alphanumeric() && c != '_')
+                .next()
+        })
+        .filter(|key| key.starts_with(char::is_alphabetic));
+
+    if let Some(key) = key {
+        return key.to_lowercase();
+    }
+
+    let all = |re: &str| {
+        let re = Regex::new(re).unwrap();
+        values.iter().all(|v| v == NULL || re.is_match(v))
+    };
+
+    if datetime_format(values).is_some() {
+        String::from("time")
+    } else if all(r"^\d{1,3}(\.\d{1,3}){3}$") {
+        String::from("ip")
+    } else if all(r"^(GET|POST|PUT|DELETE|HEAD|OPTIONS|PATCH|CONNECT|TRACE)$") {
+        String::from("method")
+    } else if all(r"^HTTP/\d(\.\d)?$") {
+        String::from("protocol")
+    } else if all(r"^(?i)(trace|debug|info|notice|warn|warning|error|err|crit|fatal|alert|emerg)$")
+    {
+        String::from("level")
+    } else if all(r"^/") {
+        String::from("path")
+    } else {
+        String::from("field")
+    }
+}
+
+fn column_type(values: &[String]) -> (&'static str, Option<String>) {
+    let values = values
+        .iter()
+        .filter(|v| *v != NULL)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if values.is_empty() {
+        ("TEXT", None)
+    } else if let Some(format) = datetime_format(&values) {
+        ("TIMESTAMP", Some(format!(r#"datetime("{}")"#, format)))
+    } else if values.iter().all(|v| v.parse::<i64>().is_ok()) {
+        ("INTEGER", Some(String::from("int")))
+    } else if values.iter().all(|v| v.parse::<f64>().is_ok()) {
+        ("FLOAT", Some(String::from("float")))
+    } else {
+        ("TEXT", None)
+    }
+}
+
+fn literal(lit: &str) -> String {
+    if ["{{", "{%", "{#"].iter().any(|tag| lit.contains(tag)) {
+        format!("{{% raw %}}{}{{% endraw %}}", lit)
+    } else {
+        lit.to_string()
+    }
+}
+
+pub fn template(lines: &[String]) -> String {
+    let lines = lines
+        .iter()
+        .map(|line| line.trim_end_matches(&['\r', '\n'][..]))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    let samples = lines.iter().map(|line| tokenize(line)).collect::<Vec<_>>();
+
+    if samples.is_empty() {
+        return String::default();
+    }
+
+    let mut names = HashSet::new();
+    let mut columns = Vec::new();
+    let mut template = String::new();
+    let mut prev = None;
+
+    for part in parts(&samples).iter() {
+        match part {
+            Part::Lit(lit) => {
+                template.push_str(&literal(lit));
+                prev = Some(lit.as_str());
+            }
+            Part::Capture(values) => {
+                let base = name(values, prev);
+                let name = (1..)
+                    .map(|n| match n {
+                        1 if base != "field" => base.clone(),
+                        n => format!("{}{}", base, n),
+                    })
+                    .find(|name| !names.contains(name))
+                    .unwrap_or_default();
+                let (sql_type, filter) = column_type(values);
+
+                match filter {
+                    Some(filter) => template.push_str(&format!("{{{{ {}|{} }}}}", name, filter)),
+                    None => template.push_str(&format!("{{{{ {} }}}}", name)),
+                }
+
+                columns.push(format!("{} {}", name, sql_type));
+                names.insert(name);
+                prev = None;
+            }
+        }
+    }
+
+    format!(
+        "{{# inferred from {} sample lines #}}\n{{# columns: {} #}}\n{}\n",
+        lines.len(),
+        columns.join(", "),
+        template
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::select;
+    use crate::parser;
+    use futures_await_test::async_test;
+    use gluesql::data::Value;
+
+    #[test]
+    fn it_infer() {
+        let lines = [
+            r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#,
+            r#"10.0.0.2 - - [10/Oct/2000:13:56:01 -0700] "POST /login HTTP/1.1" 302 -"#,
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            concat!(
+                "{# inferred from 2 sample lines #}\n",
+                "{# columns: ip TEXT, field1 TEXT, time TIMESTAMP, method TEXT, path TEXT, protocol TEXT, field2 INTEGER, field3 INTEGER #}\n",
+                r#"{{ ip }} - {{ field1 }} [{{ time|datetime("%d/%b/%Y:%H:%M:%S %z") }}] "{{ method }} {{ path }} {{ protocol }}" {{ field2|int }} {{ field3|int }}"#,
+                "\n"
+            ),
+            template(&lines)
+        );
+
+        let lines = ["level=info msg=started", "level=warn msg=slow request"]
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+
+        assert!(template(&lines).ends_with("level={{ level }} msg={{ msg }}\n"));
+    }
+
+    #[async_test]
+    async fn it_infer_round_trip() {
+        let lines = [
+            "2021-09-09 06:04:05 INFO [worker-1] took 12.5 ms",
+            "2021-09-09 06:04:06 ERROR [worker-2] took 3 ms",
+        ];
+        let template = template(
+            &lines
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>(),
+        );
+        let template = parser::join_lines(&template, &parser::Syntax::default());
+
+        assert_eq!(
+            r#"{{ time|datetime("%Y-%m-%d %H:%M:%S%.f") }} {{ level }} [{{ field1 }}] took {{ field2|float }} ms"#,
+            template
+        );
+
+        let rows = select(&[&template], &lines, "SELECT level, field2 FROM kaeshi").await;
+        assert_eq!(
+            vec![
+                vec![Value::Str("INFO".to_string()), Value::F64(12.5)],
+                vec![Value::Str("ERROR".to_string()), Value::F64(3.0)]
+            ],
+            rows
+        );
+    }
+}
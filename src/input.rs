@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+
+use super::filter;
+use crate::InputFormat;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputSource {
+    pub file: String,
+    pub template: String,
+    pub table: String,
+}
+
+impl std::str::FromStr for InputSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        // A Windows drive (`C:/`) belongs to the file, not to the separators.
+        let (drive, rest) = match s.as_bytes() {
+            [d, b':', b'/' | b'\\', ..] if d.is_ascii_alphabetic() => s.split_at(2),
+            _ => ("", s),
+        };
+        let parts = rest.rsplitn(3, ':').collect::<Vec<_>>();
+        let (file, template, table) = match parts[..] {
+            [table, template, file] if !file.is_empty() && !template.is_empty() => {
+                (file, template, table.to_string())
+            }
+            [template, file] if !file.is_empty() && !template.is_empty() => {
+                let stem = std::path::Path::new(file)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                (file, template, column_name(&stem, 0))
+            }
+            _ => return Err(anyhow::anyhow!("expected FILE:TEMPLATE[:TABLE], got {}", s)),
+        };
+
+        Ok(InputSource {
+            file: format!("{}{}", drive, file),
+            template: template.to_string(),
+            table,
+        })
+    }
+}
+
+impl InputSource {
+    /// Fails when two sources load into the same table.
+    pub fn check_tables(sources: &[InputSource]) -> anyhow::Result<()> {
+        let mut tables = std::collections::BTreeSet::new();
+
+        for source in sources {
+            if !tables.insert(source.table.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "table {} is loaded by more than one --input; name the tables with FILE:TEMPLATE:TABLE",
+                    source.table
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Decoder {
+    format: InputFormat,
+    header: Option<Vec<String>>,
+}
+
+fn column_name(name: &str, n: usize) -> String {
+    let name = filter::identifier(name);
+
+    if name.is_empty() {
+        format!("column{}", n + 1)
+    } else {
+        name
+    }
+}
+
+fn columns<'k, I: IntoIterator<Item = &'k str>>(keys: I) -> Vec<String> {
+    filter::unique(
+        keys.into_iter()
+            .enumerate()
+            .map(|(n, key)| column_name(key, n)),
+    )
+}
+
+impl Decoder {
+    pub fn new(format: InputFormat) -> Self {
+        Decoder {
+            format,
+            header: None,
+        }
+    }
+
+    pub fn incomplete(&self, record: &str) -> bool {
+        match self.format {
+            InputFormat::Csv => record.matches('"').count() % 2 == 1,
+            _ => false,
+        }
+    }
+
+    fn fields(&self, record: &str) -> Option<Vec<String>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(match self.format {
+                InputFormat::Tsv => b'\t',
+                _ => b',',
+            })
+            .quoting(matches!(self.format, InputFormat::Csv))
+            .from_reader(record.as_bytes());
+
+        reader
+            .records()
+            .next()?
+            .ok()
+            .map(|r| r.iter().map(String::from).collect())
+    }
+
+    pub fn decode(&mut self, record: &str) -> Option<BTreeMap<String, String>> {
+        let record = record.trim_end_matches(&['\r', '\n'][..]);
+
+        if record.is_empty() {
+            return None;
+        }
+
+        match self.format {
+            InputFormat::Ndjson => match filter::json(record)? {
+                serde_json::Value::Object(map) => Some(
+                    columns(map.keys().map(String::as_str))
+                        .into_iter()
+                        .zip(map.values().map(filter::json_text))
+                        .collect(),
+                ),
+                _ => None,
+            },
+            InputFormat::Ltsv => {
+                let pairs = record
+                    .split('\t')
+                    .filter_map(|field| field.split_once(':'))
+                    .collect::<Vec<_>>();
+
+                Some(
+                    columns(pairs.iter().map(|(key, _)| *key))
+                        .into_iter()
+                        .zip(pairs.iter().map(|(_, value)| value.to_string()))
+                        .collect(),
+                )
+            }
+            InputFormat::Csv | InputFormat::Tsv => {
+                let fields = self.fields(record)?;
+
+                match &self.header {
+                    None => {
+                        self.header = Some(columns(fields.iter().map(String::as_str)));
+                        None
+                    }
+                    Some(header) => Some(
+                        fields
+                            .into_iter()
+                            .enumerate()
+                            .map(|(n, value)| match header.get(n) {
+                                Some(name) => (name.clone(), value),
+                                None => (column_name("", n), value),
+                            })
+                            .collect(),
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(format: InputFormat, records: &[&str]) -> Vec<BTreeMap<String, String>> {
+        let mut decoder = Decoder::new(format);
+        records.iter().filter_map(|r| decoder.decode(r)).collect()
+    }
+
+    fn row(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn it_decode() {
+        assert_eq!(
+            vec![row(&[("id", "1"), ("user", r#"{"name":"a"}"#)])],
+            decode(
+                InputFormat::Ndjson,
+                &[
+                    "{\"id\": 1, \"user\": {\"name\": \"a\"}}\n",
+                    "[1]\n",
+                    "oops\n"
+                ]
+            )
+        );
+        assert_eq!(
+            vec![
+                row(&[("first_name", "a, b"), ("note", "say \"hi\"\nbye")]),
+                row(&[("first_name", "c"), ("note", ""), ("column3", "x")])
+            ],
+            decode(
+                InputFormat::Csv,
+                &[
+                    "first name,note\n",
+                    "\"a, b\",\"say \"\"hi\"\"\nbye\"\n",
+                    "c,,x\n"
+                ]
+            )
+        );
+        assert_eq!(
+            vec![row(&[("a", "\"1\""), ("b", "2")])],
+            decode(InputFormat::Tsv, &["a\tb\n", "\"1\"\t2\n"])
+        );
+        assert_eq!(
+            vec![row(&[
+                ("host", "127.0.0.1"),
+                ("time", "[10/Oct/2000:13:55:36]")
+            ])],
+            decode(
+                InputFormat::Ltsv,
+                &["host:127.0.0.1\ttime:[10/Oct/2000:13:55:36]\n"]
+            )
+        );
+        assert_eq!(
+            vec![row(&[("_1st", "2"), ("__", "3"), ("order", "1")])],
+            decode(
+                InputFormat::Ndjson,
+                &["{\"order\": 1, \"1st\": 2, \"名前\": 3}\n"]
+            )
+        );
+        assert_eq!(
+            vec![row(&[("a_b", "1"), ("a_b_2", "2"), ("column3", "3")])],
+            decode(InputFormat::Csv, &["a-b,a b,\n", "1,2,3\n"])
+        );
+        assert_eq!(
+            vec![row(&[("_1st", "1"), ("__", "2"), ("___2", "3")])],
+            decode(InputFormat::Ltsv, &["1st:1\t名前:2\tキー:3\n"])
+        );
+        assert!(Decoder::new(InputFormat::Csv).incomplete("\"a\nb"));
+    }
+
+    #[test]
+    fn it_input_source() {
+        let source = |file: &str, template: &str, table: &str| InputSource {
+            file: file.to_string(),
+            template: template.to_string(),
+            table: table.to_string(),
+        };
+
+        assert_eq!(
+            source("access.log", "nginx", "access"),
+            "access.log:nginx:access".parse().unwrap()
+        );
+        assert_eq!(
+            source("logs/app-1.log", "tmpl.kt", "app_1"),
+            "logs/app-1.log:tmpl.kt".parse().unwrap()
+        );
+        assert_eq!(
+            source("C:/app.log", "csv", "app"),
+            "C:/app.log:csv:app".parse().unwrap()
+        );
+        assert_eq!(
+            source("C:/app.log", "csv", "app"),
+            "C:/app.log:csv".parse().unwrap()
+        );
+        assert_eq!(
+            source(r"d:\logs\app.log", "nginx", "web"),
+            r"d:\logs\app.log:nginx:web".parse().unwrap()
+        );
+        assert!("C:/app.log".parse::<InputSource>().is_err());
+        assert!("app.log".parse::<InputSource>().is_err());
+
+        assert!(InputSource::check_tables(&[
+            source("a.log", "csv", "a"),
+            source("b.log", "csv", "b")
+        ])
+        .is_ok());
+        assert!(InputSource::check_tables(&[
+            source("a/app.log", "csv", "app"),
+            source("b/app.log", "csv", "app")
+        ])
+        .is_err());
+    }
+}
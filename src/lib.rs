@@ -1,12 +1,24 @@
 mod app;
 mod db;
+pub mod encoding;
+mod eval;
+mod explain;
+mod filter;
+mod formats;
+pub mod infer;
+mod input;
+pub mod output;
 mod parser;
+mod record;
 mod storage;
-pub mod output;
 
 use clap::arg_enum;
+use serde::Deserialize;
 
 pub use app::{App, AppConfig, InputToken, TokenExpr, DB};
+pub use db::IngestTime;
+pub use input::InputSource;
+pub use record::RecordMode;
 
 arg_enum! {
     #[derive(Debug)]
@@ -16,3 +28,69 @@ arg_enum! {
         JSON,
     }
 }
+
+arg_enum! {
+    #[derive(Debug, Deserialize, Clone, Copy)]
+    pub enum TimeZone {
+        Local,
+        Utc,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Deserialize, Clone, Copy)]
+    pub enum Format {
+        Clf,
+        Combined,
+        Nginx,
+        Syslog,
+        Syslog5424,
+        Logfmt,
+        Journald,
+        Haproxy,
+        PostgresCsv,
+        AwsElb,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Deserialize, Clone, Copy)]
+    pub enum InputFormat {
+        Ndjson,
+        Csv,
+        Tsv,
+        Ltsv,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Deserialize, Clone, Copy)]
+    pub enum Metadata {
+        Line,
+        Offset,
+        File,
+        Raw,
+    }
+}
+
+impl Metadata {
+    pub fn column(&self) -> String {
+        format!("_{}", self.to_string().to_lowercase())
+    }
+
+    fn column_type(&self) -> db::ColumnType {
+        match self {
+            Metadata::Line | Metadata::Offset => db::ColumnType::Int,
+            Metadata::File | Metadata::Raw => db::ColumnType::Text,
+        }
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Deserialize, Clone, Copy)]
+    pub enum TimeFormat {
+        Rfc3339,
+        EpochSeconds,
+        EpochMillis,
+    }
+}
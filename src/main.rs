@@ -1,26 +1,114 @@
+use anyhow::Context;
 use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
 use structopt::StructOpt;
 use tokio::sync::mpsc;
 
-use kaeshi::{output, App, AppConfig, InputToken, TokenExpr, DB, OutputType};
+use kaeshi::{
+    encoding, infer, output, App, AppConfig, Format, IngestTime, InputFormat, InputSource,
+    InputToken, Metadata, OutputType, RecordMode, TimeFormat, TimeZone, TokenExpr, DB,
+};
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Suggests a template for the sample lines read from stdin
+    Infer {
+        /// Number of lines to sample
+        #[structopt(long, default_value = "100")]
+        lines: usize,
+    },
+}
 
 #[derive(Debug, StructOpt)]
 struct Opt {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+
     pub file: Option<String>,
 
     #[structopt(short, long)]
     pub tags: Vec<String>,
 
+    /// Parses a common log format with a built-in template
+    #[structopt(long, possible_values = &Format::variants(), case_insensitive = true)]
+    pub format: Option<Format>,
+
+    /// Reads structured input (one JSON object per line, CSV or TSV with a header, or LTSV) without a template
+    #[structopt(long, possible_values = &InputFormat::variants(), case_insensitive = true, conflicts_with_all = &["tags", "format", "template-file", "search", "explain"])]
+    pub input_format: Option<InputFormat>,
+
+    /// Reads a template from a file; a trailing `\` joins a line with the next one
+    #[structopt(long)]
+    pub template_file: Vec<String>,
+
+    /// Loads FILE into TABLE (the file name by default) with TEMPLATE, which is a built-in format, an input format or a template file; repeat it to JOIN several inputs in one query
+    #[structopt(long, value_name = "FILE:TEMPLATE[:TABLE]", conflicts_with_all = &["tags", "format", "template-file", "input-format", "table-name"])]
+    pub input: Vec<InputSource>,
+
     #[structopt(short, long)]
     pub query: Option<String>,
 
     #[structopt(long)]
     pub table_name: Option<String>,
 
+    /// Adds a `_template` column naming the template that matched each row
+    #[structopt(long)]
+    pub template_column: bool,
+
+    /// Adds `_line`, `_offset`, `_file` and `_raw` columns locating each row in the input
+    #[structopt(long, possible_values = &Metadata::variants(), case_insensitive = true, use_delimiter = true)]
+    pub metadata: Vec<Metadata>,
+
+    /// Finds every non-overlapping match anywhere in a record instead of matching from its start
+    #[structopt(long)]
+    pub search: bool,
+
+    /// Prints to stderr how each record was matched against each template, and where matching failed
+    #[structopt(long, conflicts_with = "search")]
+    pub explain: bool,
+
+    /// Starts a new record at each line matching this regex
+    #[structopt(long, conflicts_with_all = &["record-indent", "record-blank", "record-lines"])]
+    pub record_start: Option<String>,
+
+    /// Appends indented lines to the previous record
+    #[structopt(long, conflicts_with_all = &["record-blank", "record-lines"])]
+    pub record_indent: bool,
+
+    /// Separates records by blank lines
+    #[structopt(long, conflicts_with = "record-lines")]
+    pub record_blank: bool,
+
+    /// Groups every N lines into one record
+    #[structopt(long)]
+    pub record_lines: Option<usize>,
+
     #[structopt(long, possible_values = &OutputType::variants(), case_insensitive = true)]
     pub output_type: Option<OutputType>,
 
-    pub timestamp: Option<String>,
+    /// Transcodes input from this encoding (e.g. shift_jis, euc-jp, utf-16le) to UTF-8; `auto` only reads the BOM
+    #[structopt(long)]
+    pub encoding: Option<String>,
+
+    /// Encodes output in this encoding instead of UTF-8
+    #[structopt(long)]
+    pub output_encoding: Option<String>,
+
+    /// Adds a column holding the time each row was read
+    #[structopt(long)]
+    pub ingest_time_column: Option<String>,
+
+    /// Zone of the ingestion time stored as text (Local by default); a TIMESTAMP is always stored in UTC
+    #[structopt(long, possible_values = &TimeZone::variants(), case_insensitive = true, requires = "ingest-time-as-text")]
+    pub ingest_time_zone: Option<TimeZone>,
+
+    #[structopt(long, possible_values = &TimeFormat::variants(), case_insensitive = true, default_value = "Rfc3339")]
+    pub ingest_time_format: TimeFormat,
+
+    /// Stores the ingestion time as TEXT instead of TIMESTAMP (or INTEGER for epoch formats)
+    #[structopt(long)]
+    pub ingest_time_as_text: bool,
 }
 
 #[tokio::main]
@@ -28,27 +116,122 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
     let opt = Opt::from_args();
 
+    if let Some(Command::Infer { lines }) = opt.command {
+        let lines = std::io::stdin()
+            .lock()
+            .lines()
+            .take(lines)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        print!("{}", infer::template(&lines));
+        return Ok(());
+    }
+
+    InputSource::check_tables(&opt.input)?;
+
+    let record = match (opt.record_start, opt.record_lines) {
+        (Some(pattern), _) => Some(RecordMode::Start(pattern)),
+        (_, Some(n)) => Some(RecordMode::Lines(n)),
+        _ if opt.record_indent => Some(RecordMode::Indent),
+        _ if opt.record_blank => Some(RecordMode::Blank),
+        _ => None,
+    };
+
     let config: AppConfig = {
         let mut config = AppConfig::default();
         let mut tokens = opt
             .tags
             .iter()
-            .map(|tag| TokenExpr::new_with_tag(tag))
-            .collect::<Vec<_>>();
+            .map(|tag| TokenExpr::new_with_tag(tag).expand(Path::new(".")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        for path in opt.template_file.iter() {
+            tokens.push(TokenExpr::from_file(path)?);
+        }
+
+        if let Some(format) = opt.format {
+            tokens.push(format.template());
+        }
+
+        let (zone, format, as_text) = (
+            opt.ingest_time_zone.unwrap_or(TimeZone::Local),
+            opt.ingest_time_format,
+            opt.ingest_time_as_text,
+        );
 
         config.table = opt.table_name;
-        config.timestamp = opt.timestamp;
+        config.template_column = opt.template_column;
+        config.search = opt.search;
+        config.explain = opt.explain;
+        config.input_format = opt.input_format;
+        config.metadata = opt.metadata;
+        if let Some(label) = &opt.output_encoding {
+            encoding::lookup(label)?;
+        }
+        config.encoding = match opt.encoding {
+            Some(label) => encoding::input(&label).map(|_| Some(label))?,
+            None => None,
+        };
+        config.record = record
+            .clone()
+            .or(opt.format.map(|format| format.record()))
+            .unwrap_or_default();
+        config.ingest_time = opt.ingest_time_column.map(|column| IngestTime {
+            column,
+            zone,
+            format,
+            as_text,
+        });
         config.templates.append(&mut tokens);
         config
     };
 
-    let (tx, mut rx): (
-        mpsc::UnboundedSender<InputToken>,
-        mpsc::UnboundedReceiver<InputToken>,
-    ) = mpsc::unbounded_channel();
-    let templates = config.templates.clone();
-    let app = App::new_with_config(tx, config).await?;
-    let _ret = tokio::join!(app.input_handler(), app.parse_handler(&mut rx, templates));
+    let app = if opt.input.is_empty() {
+        let (tx, mut rx): (
+            mpsc::UnboundedSender<InputToken>,
+            mpsc::UnboundedReceiver<InputToken>,
+        ) = mpsc::unbounded_channel();
+        let templates = config.templates.clone();
+        let app = App::new_with_config(tx, config).await?;
+        let (read, _parse) =
+            tokio::join!(app.input_handler(), app.parse_handler(&mut rx, templates));
+        read?;
+        app
+    } else {
+        let mut app: Option<App> = None;
+
+        for source in opt.input.iter() {
+            let mut config = config.clone();
+            config.table = Some(source.table.clone());
+            config.file = Some(source.file.clone());
+
+            if let Ok(format) = source.template.parse::<Format>() {
+                config.templates = vec![format.template()];
+                config.record = record.clone().unwrap_or_else(|| format.record());
+            } else if let Ok(format) = source.template.parse::<InputFormat>() {
+                config.input_format = Some(format);
+            } else {
+                config.templates = vec![TokenExpr::from_file(&source.template)?];
+            }
+
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let templates = config.templates.clone();
+            let next = App::new_with_config(tx, config).await?;
+            if let Some(prev) = app.take() {
+                next.db.swap(&prev.db);
+            }
+
+            let file = std::fs::File::open(&source.file).with_context(|| source.file.clone())?;
+            let file = std::io::BufReader::new(file);
+            tokio::try_join!(
+                next.read_handler(file),
+                next.parse_handler(&mut rx, templates)
+            )
+            .with_context(|| source.file.clone())?;
+            app = Some(next);
+        }
+
+        app.unwrap()
+    };
     let query = opt
         .query
         .unwrap_or(format!("SELECT * FROM {};", app.table_name()));
@@ -61,6 +244,12 @@ async fn main() -> anyhow::Result<()> {
         }) => {
             let f = |r: &gluesql::data::Value| match r {
                 gluesql::data::Value::Str(s) => (*s).clone(),
+                gluesql::data::Value::Bool(b) => b.to_string(),
+                gluesql::data::Value::I64(n) => n.to_string(),
+                gluesql::data::Value::F64(n) => n.to_string(),
+                gluesql::data::Value::Date(d) => d.to_string(),
+                gluesql::data::Value::Timestamp(t) => t.to_string(),
+                gluesql::data::Value::Time(t) => t.to_string(),
                 _ => String::default(),
             };
 
@@ -74,7 +263,19 @@ async fn main() -> anyhow::Result<()> {
                 })
                 .collect::<Vec<_>>();
                 
-            output::print(std::io::stdout(), &records, opt.output_type.unwrap_or(OutputType::Table))?;
+            let output_type = opt.output_type.unwrap_or(OutputType::Table);
+
+            match opt.output_encoding {
+                Some(label) => {
+                    let mut buf = Vec::default();
+                    output::print(&mut buf, &records, output_type)?;
+                    std::io::stdout()
+                        .write_all(&encoding::encode(&String::from_utf8_lossy(&buf), &label)?)?;
+                }
+                None => {
+                    output::print(std::io::stdout(), &records, output_type)?;
+                }
+            }
         }
         _ => {}
     };
@@ -1,11 +1,15 @@
+use anyhow::anyhow;
 use nom::branch::alt;
-use nom::bytes::complete::{escaped, is_not, tag};
+use nom::bytes::complete::{escaped, is_not, tag, take_until};
 use nom::character::complete::{anychar, char, digit1};
 use nom::combinator::{complete, map, opt};
 use nom::error::ParseError;
 use nom::multi::{many0, separated_list};
 use nom::sequence::{delimited, pair, tuple};
 use nom::{self, error_position, Compare, IResult, InputTake};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::str;
 
 #[cfg(feature = "serde")]
@@ -17,6 +21,8 @@ pub struct Syntax {
     pub block_end: String,
     pub expr_start: String,
     pub expr_end: String,
+    pub comment_start: String,
+    pub comment_end: String,
 }
 
 impl Default for Syntax {
@@ -26,6 +32,8 @@ impl Default for Syntax {
             block_end: String::from("%}"),
             expr_start: String::from("{{"),
             expr_end: String::from("}}"),
+            comment_start: String::from("{#"),
+            comment_end: String::from("#}"),
         }
     }
 }
@@ -52,12 +60,21 @@ pub enum Target<'a> {
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct WS(pub bool, pub bool);
 
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Quantifier {
+    #[default]
+    Lazy,
+    Greedy,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Node<'a> {
     Lit(&'a str, &'a str, &'a str),
-    Expr(WS, Expr<'a>),
+    Expr(WS, Expr<'a>, Quantifier),
     Cond(Vec<(WS, Option<Expr<'a>>, Vec<Node<'a>>)>, WS),
     Loop(WS, Target<'a>, Expr<'a>, Vec<Node<'a>>, WS),
+    Optional(WS, Vec<Node<'a>>, WS),
+    Ws,
 }
 
 pub type Cond<'a> = (WS, Option<Expr<'a>>, Vec<Node<'a>>);
@@ -122,19 +139,21 @@ fn take_content<'a>(i: &'a [u8], s: &'a Syntax) -> ParserError<'a, Node<'a>> {
     let be = s.block_start.as_bytes()[1];
     let es = s.expr_start.as_bytes()[0];
     let ee = s.expr_start.as_bytes()[1];
+    let cs = s.comment_start.as_bytes()[0];
+    let ce = s.comment_start.as_bytes()[1];
 
     let mut state = Start;
     for (idx, c) in i.iter().enumerate() {
         state = match state {
             Start => {
-                if *c == bs || *c == es {
+                if *c == bs || *c == es || *c == cs {
                     Brace(idx)
                 } else {
                     Any
                 }
             }
             Any => {
-                if *c == bs || *c == es {
+                if *c == bs || *c == es || *c == cs {
                     Brace(idx)
                 } else if *c == b'\n' {
                     End(idx + 1)
@@ -143,7 +162,7 @@ fn take_content<'a>(i: &'a [u8], s: &'a Syntax) -> ParserError<'a, Node<'a>> {
                 }
             }
             Brace(start) => {
-                if *c == be || *c == ee {
+                if *c == be || *c == ee || *c == ce {
                     End(start)
                 } else {
                     Any
@@ -252,8 +271,13 @@ fn arguments(i: &[u8]) -> IResult<&[u8], Vec<Expr>> {
     delimited(tag("("), separated_list(tag(","), ws(expr_any)), tag(")"))(i)
 }
 
+fn expr_group(i: &[u8]) -> IResult<&[u8], Expr> {
+    delimited(tag("("), ws(expr_any), tag(")"))(i)
+}
+
 fn expr_single(i: &[u8]) -> IResult<&[u8], Expr> {
     alt((
+        expr_group,
         expr_bool_lit,
         expr_num_lit,
         expr_str_lit,
@@ -299,36 +323,30 @@ fn expr_unary(i: &[u8]) -> IResult<&[u8], Expr> {
 macro_rules! expr_prec_layer {
     ( $name:ident, $inner:ident, $op:expr ) => {
         fn $name(i: &[u8]) -> IResult<&[u8], Expr> {
-            let (i, (left, op_and_right)) = tuple((
+            let (i, (left, op_and_rights)) = tuple((
                 $inner,
-                opt(pair(
+                many0(pair(
                     ws(tag($op)),
-                    expr_any,
+                    $inner,
                 ))
             ))(i)?;
-            Ok((i, match op_and_right {
-                Some((op, right)) => Expr::BinOp(
-                    str::from_utf8(op).unwrap(), Box::new(left), Box::new(right)
-                ),
-                None => left,
-            }))
+            Ok((i, op_and_rights.into_iter().fold(left, |left, (op, right)| Expr::BinOp(
+                str::from_utf8(op).unwrap(), Box::new(left), Box::new(right)
+            ))))
         }
     };
     ( $name:ident, $inner:ident, $( $op:expr ),+ ) => {
         fn $name(i: &[u8]) -> IResult<&[u8], Expr> {
-            let (i, (left, op_and_right)) = tuple((
+            let (i, (left, op_and_rights)) = tuple((
                 $inner,
-                opt(pair(
+                many0(pair(
                     ws(alt(($( tag($op) ),*,))),
-                    expr_any
+                    $inner
                 ))
             ))(i)?;
-            Ok((i, match op_and_right {
-                Some((op, right)) => Expr::BinOp(
-                    str::from_utf8(op).unwrap(), Box::new(left), Box::new(right)
-                ),
-                None => left,
-            }))
+            Ok((i, op_and_rights.into_iter().fold(left, |left, (op, right)| Expr::BinOp(
+                str::from_utf8(op).unwrap(), Box::new(left), Box::new(right)
+            ))))
         }
     }
 }
@@ -368,11 +386,19 @@ fn expr_node<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Node<'a>> {
         |i| tag_expr_start(i, s),
         opt(tag("-")),
         ws(expr_any),
+        opt(ws(alt((tag("+"), tag("?"))))),
         opt(tag("-")),
         |i| tag_expr_end(i, s),
     ));
-    let (i, (_, pws, expr, nws, _)) = p(i)?;
-    Ok((i, Node::Expr(WS(pws.is_some(), nws.is_some()), expr)))
+    let (i, (_, pws, expr, quantifier, nws, _)) = p(i)?;
+    let quantifier = match quantifier {
+        Some(b"+") => Quantifier::Greedy,
+        _ => Quantifier::Lazy,
+    };
+    Ok((
+        i,
+        Node::Expr(WS(pws.is_some(), nws.is_some()), expr, quantifier),
+    ))
 }
 
 fn cond_if(i: &[u8]) -> IResult<&[u8], Expr> {
@@ -443,10 +469,73 @@ fn block_for<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Node<'a>> {
     ))
 }
 
+fn block_optional<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Node<'a>> {
+    let p = tuple((
+        opt(tag("-")),
+        ws(tag("optional")),
+        opt(tag("-")),
+        |i| tag_block_end(i, s),
+        |i| parse_template(i, s),
+        |i| tag_block_start(i, s),
+        opt(tag("-")),
+        ws(tag("endoptional")),
+        opt(tag("-")),
+    ));
+    let (i, (pws1, _, nws1, _, block, _, pws2, _, nws2)) = p(i)?;
+    Ok((
+        i,
+        Node::Optional(
+            WS(pws1.is_some(), nws1.is_some()),
+            block,
+            WS(pws2.is_some(), nws2.is_some()),
+        ),
+    ))
+}
+
+fn comment<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Node<'a>> {
+    let (i, _) = tag(s.comment_start.as_str())(i)?;
+    let (i, _) = take_until(s.comment_end.as_str())(i)?;
+    let (i, _) = tag(s.comment_end.as_str())(i)?;
+    Ok((i, Node::Lit("", "", "")))
+}
+
+fn block_raw<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Node<'a>> {
+    let start = tuple((
+        |i| tag_block_start(i, s),
+        opt(tag("-")),
+        ws(tag("raw")),
+        opt(tag("-")),
+        |i| tag_block_end(i, s),
+    ));
+    let end = tuple((
+        |i| tag_block_start(i, s),
+        opt(tag("-")),
+        ws(tag("endraw")),
+        opt(tag("-")),
+        |i| tag_block_end(i, s),
+    ));
+    let (i, _) = start(i)?;
+
+    for idx in 0..i.len() {
+        if let Ok((rest, _)) = end(&i[idx..]) {
+            return Ok((rest, split_ws_parts(&i[..idx])));
+        }
+    }
+
+    Err(nom::Err::Error(error_position!(
+        i,
+        nom::error::ErrorKind::TakeUntil
+    )))
+}
+
 fn block_node<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Node<'a>> {
     let p = tuple((
         |i| tag_block_start(i, s),
-        alt((|i| block_if(i, s), |i| block_for(i, s))),
+        alt((
+            |i| block_if(i, s),
+            |i| block_for(i, s),
+            |i| block_optional(i, s),
+        )),
         |i| tag_block_end(i, s),
     ));
     let (i, (_, contents, _)) = p(i)?;
@@ -455,11 +544,437 @@ fn block_node<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Node<'a>> {
 }
 
 pub fn parse_template<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Vec<Node<'a>>> {
-    many0(alt((
+    let (i, nodes) = many0(alt((
         complete(|i| take_content(i, s)),
         complete(|i| expr_node(i, s)),
+        complete(|i| comment(i, s)),
+        complete(|i| block_raw(i, s)),
         complete(|i| block_node(i, s)),
-    )))(i)
+    )))(i)?;
+
+    Ok((
+        i,
+        nodes
+            .into_iter()
+            .filter(|node| *node != Node::Lit("", "", ""))
+            .collect(),
+    ))
+}
+
+pub fn parse<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], Vec<Node<'a>>> {
+    let (i, nodes) = parse_template(i, s)?;
+    Ok((i, trim_ws(nodes, false, false)))
+}
+
+fn ws_before(node: &Node) -> bool {
+    match node {
+        Node::Expr(WS(ws, _), _, _) => *ws,
+        Node::Cond(exprs, _) => exprs.first().is_some_and(|(WS(ws, _), _, _)| *ws),
+        Node::Loop(WS(ws, _), _, _, _, _) | Node::Optional(WS(ws, _), _, _) => *ws,
+        _ => false,
+    }
+}
+
+fn ws_after(node: &Node) -> bool {
+    match node {
+        Node::Expr(WS(_, ws), _, _) => *ws,
+        Node::Cond(_, WS(_, ws)) | Node::Loop(_, _, _, _, WS(_, ws)) => *ws,
+        Node::Optional(_, _, WS(_, ws)) => *ws,
+        _ => false,
+    }
+}
+
+fn trim_lit<'a>(lit: (&'a str, &'a str, &'a str), left: bool, right: bool) -> Node<'a> {
+    let (mut a, mut b, mut c) = lit;
+
+    if left {
+        a = "";
+        if b.is_empty() {
+            c = c.trim_start();
+        }
+    }
+
+    if right {
+        c = c.trim_end();
+        if c.is_empty() {
+            b = b.trim_end();
+            if b.is_empty() {
+                a = "";
+            }
+        }
+    }
+
+    Node::Lit(a, b, c)
+}
+
+fn trim_ws(nodes: Vec<Node>, start: bool, end: bool) -> Vec<Node> {
+    let lefts = (0..nodes.len())
+        .map(|idx| {
+            if idx == 0 {
+                start
+            } else {
+                ws_after(&nodes[idx - 1])
+            }
+        })
+        .collect::<Vec<_>>();
+    let rights = (0..nodes.len())
+        .map(|idx| nodes.get(idx + 1).map_or(end, ws_before))
+        .collect::<Vec<_>>();
+    let mut res: Vec<Node> = Vec::new();
+
+    for (idx, node) in nodes.into_iter().enumerate() {
+        let (left, right) = (lefts[idx], rights[idx]);
+
+        if left && res.last() != Some(&Node::Ws) {
+            res.push(Node::Ws);
+        }
+
+        let node = match node {
+            Node::Lit(a, b, c) => trim_lit((a, b, c), left, right),
+            Node::Cond(exprs, ws) => {
+                let ends = exprs
+                    .iter()
+                    .skip(1)
+                    .map(|(WS(pws, _), _, _)| *pws)
+                    .chain(Some(ws.0))
+                    .collect::<Vec<_>>();
+                let exprs = exprs
+                    .into_iter()
+                    .zip(ends)
+                    .map(|((cws, cond, block), end)| (cws, cond, trim_ws(block, cws.1, end)))
+                    .collect();
+                Node::Cond(exprs, ws)
+            }
+            Node::Loop(ws1, var, iter, block, ws2) => {
+                let block = trim_ws(block, ws1.1, ws2.0);
+                Node::Loop(ws1, var, iter, block, ws2)
+            }
+            Node::Optional(ws1, block, ws2) => {
+                Node::Optional(ws1, trim_ws(block, ws1.1, ws2.0), ws2)
+            }
+            node => node,
+        };
+
+        match node {
+            Node::Lit("", "", "") => {}
+            node => res.push(node),
+        }
+
+        if right && res.last() != Some(&Node::Ws) {
+            res.push(Node::Ws);
+        }
+    }
+
+    res
+}
+
+pub fn expand(source: &str, s: &Syntax, dir: &Path) -> anyhow::Result<String> {
+    let source = expand_includes(source, s, dir, 0)?;
+    let mut macros = BTreeMap::new();
+    let body = rewrite(&source, s, |i| {
+        let (rest, (name, params)) = macro_tag(i, s).ok()?;
+        let end = (0..rest.len()).find(|idx| endmacro_tag(&rest[*idx..], s).is_ok())?;
+        let (after, _) = endmacro_tag(&rest[end..], s).ok()?;
+        let after = after.strip_prefix(b"\n").unwrap_or(after);
+
+        let body = str::from_utf8(&rest[..end]).ok()?.to_string();
+        macros.insert(name.to_string(), (params, body));
+        Some((i.len() - after.len(), String::default()))
+    })?;
+
+    expand_calls(&body, s, &macros, 0)
+}
+
+const MAX_DEPTH: usize = 16;
+
+pub fn join_lines(source: &str, s: &Syntax) -> String {
+    let mut res = String::default();
+    let mut lines = source.lines();
+
+    while let Some(line) = lines.next() {
+        let mut line = line;
+
+        if line.trim().starts_with(s.comment_start.as_str())
+            && line.trim().ends_with(s.comment_end.as_str())
+        {
+            continue;
+        }
+
+        while let Some(head) = line.strip_suffix('\\') {
+            res.push_str(head);
+            line = lines.next().unwrap_or_default().trim_start();
+        }
+
+        res.push_str(line);
+        res.push('\n');
+    }
+
+    res.strip_suffix('\n').unwrap_or(&res).to_string()
+}
+
+fn expand_includes(source: &str, s: &Syntax, dir: &Path, depth: usize) -> anyhow::Result<String> {
+    let mut error = None;
+    let res = rewrite(source, s, |i| {
+        let (rest, path) = include_tag(i, s).ok()?;
+        let path = dir.join(path);
+        let included = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))
+            .and_then(|included| match depth {
+                MAX_DEPTH => Err(anyhow!("{}: includes nested too deeply", path.display())),
+                _ => expand_includes(
+                    &join_lines(&included, s),
+                    s,
+                    path.parent().unwrap_or(dir),
+                    depth + 1,
+                ),
+            });
+
+        match included {
+            Ok(included) => Some((i.len() - rest.len(), included)),
+            Err(e) => {
+                error.get_or_insert(e);
+                None
+            }
+        }
+    })?;
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(res),
+    }
+}
+
+fn expand_calls(
+    source: &str,
+    s: &Syntax,
+    macros: &BTreeMap<String, (Vec<&str>, String)>,
+    depth: usize,
+) -> anyhow::Result<String> {
+    let mut error = None;
+    let res = rewrite(source, s, |i| {
+        let (rest, (name, args)) = call_tag(i, s).ok()?;
+
+        let body = match macros.get(name) {
+            Some((params, _)) if params.len() != args.len() => Err(anyhow!(
+                "macro {} takes {} arguments but {} were given",
+                name,
+                params.len(),
+                args.len()
+            )),
+            Some(_) if depth == MAX_DEPTH => Err(anyhow!("macro {} nested too deeply", name)),
+            Some((params, body)) => rename(body, s, params, &args)
+                .and_then(|body| expand_calls(&body, s, macros, depth + 1)),
+            None => Err(anyhow!("unknown macro {}", name)),
+        };
+
+        match body {
+            Ok(body) => Some((i.len() - rest.len(), body)),
+            Err(e) => {
+                error.get_or_insert(e);
+                None
+            }
+        }
+    })?;
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(res),
+    }
+}
+
+fn rename(body: &str, s: &Syntax, params: &[&str], args: &[&str]) -> anyhow::Result<String> {
+    if params.is_empty() {
+        return Ok(body.to_string());
+    }
+
+    let tags = Regex::new(&format!(
+        "(?s){}.*?{}|{}.*?{}",
+        regex::escape(&s.expr_start),
+        regex::escape(&s.expr_end),
+        regex::escape(&s.block_start),
+        regex::escape(&s.block_end),
+    ))?;
+    let names = params
+        .iter()
+        .cloned()
+        .zip(args.iter().cloned())
+        .collect::<BTreeMap<_, _>>();
+    let mut res = String::with_capacity(body.len());
+    let mut idx = 0;
+
+    // Only variables are renamed, so a parameter named like a filter or a keyword leaves those alone.
+    for m in tags.find_iter(body) {
+        for var in tag_vars(m.as_str().as_bytes(), s) {
+            if let Some(arg) = names.get(var) {
+                let start = var.as_ptr() as usize - body.as_ptr() as usize;
+                res.push_str(&body[idx..start]);
+                res.push_str(arg);
+                idx = start + var.len();
+            }
+        }
+    }
+
+    res.push_str(&body[idx..]);
+    Ok(res)
+}
+
+// The variables a single tag reads, and the names it binds, in source order.
+fn tag_vars<'a>(i: &'a [u8], s: &'a Syntax) -> Vec<&'a str> {
+    let mut vars = Vec::default();
+
+    if let Ok((_, Node::Expr(_, expr, _))) = expr_node(i, s) {
+        expr_vars(&expr, &mut vars);
+    } else if let Ok((_, (_, args))) = call_tag(i, s) {
+        vars.extend(args);
+    } else if let Ok((_, (_, _, (target, expr), _, _))) = tuple((
+        |i| tag_block_start(i, s),
+        opt(tag("-")),
+        block_header,
+        opt(tag("-")),
+        |i| tag_block_end(i, s),
+    ))(i)
+    {
+        match target {
+            Some(Target::Name(name)) => vars.push(name),
+            Some(Target::Tuple(names)) => vars.extend(names),
+            None => {}
+        }
+
+        match expr {
+            Expr::Var("repeat") => {}
+            Expr::Filter("sep", args) if args.first() == Some(&Expr::Var("repeat")) => args
+                .iter()
+                .skip(1)
+                .for_each(|arg| expr_vars(arg, &mut vars)),
+            expr => expr_vars(&expr, &mut vars),
+        }
+    }
+
+    vars.sort_by_key(|var| var.as_ptr());
+    vars
+}
+
+fn block_header<'a>(i: &'a [u8]) -> IResult<&'a [u8], (Option<Target<'a>>, Expr<'a>)> {
+    alt((
+        map(cond_if, |expr| (None, expr)),
+        map(tuple((ws(tag("else")), cond_if)), |(_, expr)| (None, expr)),
+        map(
+            tuple((
+                ws(tag("for")),
+                ws(alt((target_single, target_tuple))),
+                ws(tag("in")),
+                ws(expr_any),
+            )),
+            |(_, target, _, expr)| (Some(target), expr),
+        ),
+    ))(i)
+}
+
+fn expr_vars<'a>(expr: &Expr<'a>, vars: &mut Vec<&'a str>) {
+    match expr {
+        Expr::Var(name) => vars.push(name),
+        Expr::Filter(_, args) => args.iter().for_each(|arg| expr_vars(arg, vars)),
+        Expr::Unary(_, expr) => expr_vars(expr, vars),
+        Expr::Range(_, start, end) => start
+            .iter()
+            .chain(end.iter())
+            .for_each(|expr| expr_vars(expr, vars)),
+        Expr::BinOp(_, left, right) => {
+            expr_vars(left, vars);
+            expr_vars(right, vars);
+        }
+        _ => {}
+    }
+}
+
+fn rewrite<'a, F>(source: &'a str, s: &Syntax, mut f: F) -> anyhow::Result<String>
+where
+    F: FnMut(&'a [u8]) -> Option<(usize, String)>,
+{
+    let i = source.as_bytes();
+    let mut res = Vec::with_capacity(i.len());
+    let mut idx = 0;
+
+    while idx < i.len() {
+        if !i[idx..].starts_with(s.block_start.as_bytes()) {
+            res.push(i[idx]);
+            idx += 1;
+            continue;
+        }
+
+        if let Ok((rest, _)) = block_raw(&i[idx..], s) {
+            let len = i.len() - idx - rest.len();
+            res.extend_from_slice(&i[idx..idx + len]);
+            idx += len;
+            continue;
+        }
+
+        match f(&i[idx..]) {
+            Some((len, replacement)) => {
+                res.extend_from_slice(replacement.as_bytes());
+                idx += len;
+            }
+            None => {
+                res.push(i[idx]);
+                idx += 1;
+            }
+        }
+    }
+
+    Ok(String::from_utf8(res)?)
+}
+
+fn include_tag<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], &'a str> {
+    let (i, (_, _, _, path, _, _)) = tuple((
+        |i| tag_block_start(i, s),
+        opt(tag("-")),
+        ws(tag("include")),
+        ws(str_lit),
+        opt(tag("-")),
+        |i| tag_block_end(i, s),
+    ))(i)?;
+    Ok((i, path))
+}
+
+fn parameters(i: &[u8]) -> IResult<&[u8], Vec<&str>> {
+    delimited(tag("("), separated_list(tag(","), ws(identifier)), tag(")"))(i)
+}
+
+fn macro_tag<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], (&'a str, Vec<&'a str>)> {
+    let (i, (_, _, _, name, params, _, _)) = tuple((
+        |i| tag_block_start(i, s),
+        opt(tag("-")),
+        ws(tag("macro")),
+        identifier,
+        ws(parameters),
+        opt(tag("-")),
+        |i| tag_block_end(i, s),
+    ))(i)?;
+    Ok((i, (name, params)))
+}
+
+fn endmacro_tag<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], &'a [u8]> {
+    let (i, (_, _, end, _, _)) = tuple((
+        |i| tag_block_start(i, s),
+        opt(tag("-")),
+        ws(tag("endmacro")),
+        opt(tag("-")),
+        |i| tag_block_end(i, s),
+    ))(i)?;
+    Ok((i, end))
+}
+
+fn call_tag<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], (&'a str, Vec<&'a str>)> {
+    let (i, (_, _, _, name, args, _, _)) = tuple((
+        |i| tag_block_start(i, s),
+        opt(tag("-")),
+        ws(tag("call")),
+        identifier,
+        ws(parameters),
+        opt(tag("-")),
+        |i| tag_block_end(i, s),
+    ))(i)?;
+    Ok((i, (name, args)))
 }
 
 fn tag_block_start<'a>(i: &'a [u8], s: &'a Syntax) -> IResult<&'a [u8], &'a [u8]> {
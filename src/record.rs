@@ -0,0 +1,147 @@
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub enum RecordMode {
+    #[default]
+    Line,
+    Start(String),
+    Indent,
+    Blank,
+    Lines(usize),
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub offset: usize,
+}
+
+pub struct Assembler {
+    mode: RecordMode,
+    start: Option<Regex>,
+    lines: Vec<String>,
+    next: Position,
+    position: Position,
+}
+
+impl Assembler {
+    pub fn new(mode: &RecordMode) -> anyhow::Result<Self> {
+        let start = match mode {
+            RecordMode::Start(pattern) => Some(Regex::new(pattern)?),
+            _ => None,
+        };
+
+        Ok(Assembler {
+            mode: mode.clone(),
+            start,
+            lines: Vec::default(),
+            next: Position { line: 1, offset: 0 },
+            position: Position::default(),
+        })
+    }
+
+    /// Adds a line that took `len` bytes of the input.
+    pub fn push(&mut self, line: String, len: usize) -> Option<(String, Position)> {
+        let is_blank = line.trim().is_empty();
+        let starts_record = match &self.mode {
+            RecordMode::Line => true,
+            RecordMode::Start(_) => self
+                .start
+                .as_ref()
+                .is_none_or(|re| re.is_match(line.trim_end_matches(&['\r', '\n'][..]))),
+            RecordMode::Indent => !line.starts_with(&[' ', '\t'][..]),
+            RecordMode::Blank => is_blank,
+            RecordMode::Lines(n) => self.lines.len() >= (*n).max(1),
+        };
+
+        let record = if starts_record { self.finish() } else { None };
+
+        let position = self.next;
+        self.next = Position {
+            line: position.line + 1,
+            offset: position.offset + len,
+        };
+
+        match self.mode {
+            RecordMode::Blank if is_blank => {}
+            _ => {
+                if self.lines.is_empty() {
+                    self.position = position;
+                }
+                self.lines.push(line)
+            }
+        }
+
+        match self.mode {
+            RecordMode::Line => self.finish(),
+            _ => record,
+        }
+    }
+
+    pub fn finish(&mut self) -> Option<(String, Position)> {
+        if self.lines.is_empty() {
+            None
+        } else {
+            Some((self.lines.drain(..).collect::<String>(), self.position))
+        }
+    }
+}
+
+#[allow(unused_imports)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_assemble() {
+        let assemble = |mode: RecordMode, lines: &[&str]| {
+            let mut assembler = Assembler::new(&mode).unwrap();
+            let mut records = lines
+                .iter()
+                .filter_map(|line| assembler.push(format!("{}\n", line), line.len() + 1))
+                .collect::<Vec<_>>();
+            records.extend(assembler.finish());
+            records
+                .into_iter()
+                .map(|(record, _)| record)
+                .collect::<Vec<_>>()
+        };
+
+        let trace = ["ERROR boom", "\tat a", "\tat b", "INFO ok"];
+        assert_eq!(
+            vec!["ERROR boom\n\tat a\n\tat b\n", "INFO ok\n"],
+            assemble(RecordMode::Indent, &trace)
+        );
+        assert_eq!(
+            vec!["ERROR boom\n\tat a\n\tat b\n", "INFO ok\n"],
+            assemble(RecordMode::Start(String::from("^[A-Z]+ ")), &trace)
+        );
+        assert_eq!(
+            vec!["a\nb\n", "c\n"],
+            assemble(RecordMode::Blank, &["a", "b", "", "", "c"])
+        );
+        assert_eq!(
+            vec!["a\nb\n", "c\n"],
+            assemble(RecordMode::Lines(2), &["a", "b", "c"])
+        );
+    }
+
+    #[test]
+    fn it_position() {
+        let mut assembler = Assembler::new(&RecordMode::Blank).unwrap();
+        let mut positions = ["a", "b", "", "", "cc", "d"]
+            .iter()
+            .filter_map(|line| assembler.push(format!("{}\n", line), line.len() + 1))
+            .map(|(_, position)| position)
+            .collect::<Vec<_>>();
+        positions.extend(assembler.finish().map(|(_, position)| position));
+
+        assert_eq!(
+            vec![
+                Position { line: 1, offset: 0 },
+                Position { line: 5, offset: 6 }
+            ],
+            positions
+        );
+    }
+}
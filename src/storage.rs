@@ -34,12 +34,12 @@ impl GStoreMut<DataKey> for MemoryStorage {}
 impl StoreMut<DataKey> for MemoryStorage {
     async fn insert_schema(self, schema: &Schema) -> MutResult<Self, ()> {
         let table_name = schema.table_name.clone();
-        
-        let mut s = HashMap::default();
-        s.insert(table_name, schema.clone());
+
+        let mut schema_map = self.schema_map;
+        schema_map.insert(table_name, schema.clone());
 
         let storage = Self {
-            schema_map: s,
+            schema_map,
             data_map: self.data_map,
             id: self.id,
         };